@@ -0,0 +1,332 @@
+use crate::kmers::KmerSet;
+use crate::query::Query;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// the samples of a conjunction as a single operand, and its negated operands as the negation
+// of their union, None if there are fewer than two of them
+fn groups(operands: &[Query]) -> (Option<Query>, Option<Query>) {
+    let mut leaves = Vec::new();
+    let mut negated = Vec::new();
+    for query in operands {
+        match query {
+            Query::Leaf(_) => leaves.push(query.clone()),
+            // subtracting a union subtracts each of its operands
+            Query::Not(inner) => match inner.as_ref() {
+                Query::Or(queries) => negated.extend(queries.iter().cloned()),
+                inner => negated.push(inner.clone()),
+            },
+            _ => {}
+        }
+    }
+    leaves.sort();
+    leaves.dedup();
+    negated.sort();
+    negated.dedup();
+    let group = |queries: Vec<Query>, and: bool| match queries.len() {
+        0 | 1 => None,
+        _ if and => Some(Query::And(queries)),
+        _ => Some(Query::Or(queries)),
+    };
+    (group(leaves, true), group(negated, false))
+}
+
+// subqueries of a query and of its operands
+fn children(query: &Query) -> &[Query] {
+    match query {
+        Query::And(queries) | Query::Or(queries) => queries,
+        Query::Not(inner) => std::slice::from_ref(inner.as_ref()),
+        Query::Leaf(_) | Query::Quorum { .. } => &[],
+    }
+}
+
+fn count_groups(query: &Query, counts: &mut HashMap<Query, usize>) {
+    if let Query::And(operands) = query {
+        let (leaves, negated) = groups(operands);
+        for group in leaves.into_iter().chain(negated) {
+            *counts.entry(group).or_default() += 1;
+        }
+    }
+    for child in children(query) {
+        count_groups(child, counts);
+    }
+}
+
+// a planned query with the groups found in several conjunctions of the batch as single operands
+// in place of their first member, and the operands of unions sorted
+fn factor_query(query: &Query, counts: &HashMap<Query, usize>) -> Query {
+    let shared = |group: Option<Query>| group.filter(|group| counts.get(group) > Some(&1));
+    match query {
+        Query::Leaf(_) | Query::Quorum { .. } => query.clone(),
+        Query::Not(inner) => Query::negate(factor_query(inner, counts)),
+        Query::Or(queries) => {
+            let mut queries: Vec<Query> = queries.iter().map(|q| factor_query(q, counts)).collect();
+            queries.sort();
+            Query::Or(queries)
+        }
+        Query::And(queries) => {
+            let (leaves, negated) = groups(queries);
+            let (mut leaves, mut negated) = (shared(leaves), shared(negated));
+            let (factor_leaves, factor_negated) = (leaves.is_some(), negated.is_some());
+            let mut operands = Vec::with_capacity(queries.len());
+            for query in queries {
+                match query {
+                    Query::Leaf(_) if factor_leaves => operands.extend(leaves.take()),
+                    Query::Not(_) if factor_negated => operands.extend(
+                        negated
+                            .take()
+                            .map(|group| Query::negate(factor_query(&group, counts))),
+                    ),
+                    query => operands.push(factor_query(query, counts)),
+                }
+            }
+            if operands.len() == 1 {
+                operands.pop().unwrap()
+            } else {
+                Query::And(operands)
+            }
+        }
+    }
+}
+
+// the planned queries of a batch, with the samples and the negated operands that several
+// conjunctions have in common grouped so they are computed once, e.g. the ALL and the NOT-ANY
+// lines shared by label files
+pub fn factor(queries: &[Query]) -> Vec<Query> {
+    let mut counts = HashMap::new();
+    for query in queries {
+        count_groups(query, &mut counts);
+    }
+    queries.iter().map(|q| factor_query(q, &counts)).collect()
+}
+
+// subqueries the evaluator computes on their own: samples are loaded, the negated operands of
+// a conjunction are subtracted and the ones that are not anchors are computed within it
+fn evaluated(query: &Query) -> Vec<&Query> {
+    let operands = children(query).iter().filter_map(|q| match (query, q) {
+        (Query::And(_), Query::Not(inner)) => Some(inner.as_ref()),
+        (Query::And(_), q) if !q.is_anchor() => None,
+        (_, q) => Some(q),
+    });
+    operands.filter(|q| !matches!(q, Query::Leaf(_))).collect()
+}
+
+// a subquery evaluated once computes the ones under it, which are not counted again
+fn count_uses(query: &Query, uses: &mut HashMap<Query, usize>) {
+    if matches!(query, Query::Leaf(_)) {
+        return;
+    }
+    let count = uses.entry(query.clone()).or_default();
+    *count += 1;
+    if *count == 1 {
+        for operand in evaluated(query) {
+            count_uses(operand, uses);
+        }
+    }
+}
+
+struct Shared {
+    // uses left, the result is dropped after the last one
+    remaining: usize,
+    cbl: Option<KmerSet>,
+}
+
+// results of the subqueries used several times by the queries of a batch, kept in memory from
+// their first to their last use, uses skipped by the evaluator included
+pub struct SharedResults {
+    state: Mutex<HashMap<Query, Shared>>,
+}
+
+impl SharedResults {
+    // the queries must be evaluated as given, planned and factored
+    pub fn new(queries: &[Query]) -> Self {
+        let mut uses = HashMap::new();
+        for query in queries {
+            count_uses(query, &mut uses);
+        }
+        let state = uses
+            .into_iter()
+            .filter(|&(_, remaining)| remaining > 1)
+            .map(|(query, remaining)| {
+                (
+                    query,
+                    Shared {
+                        remaining,
+                        cbl: None,
+                    },
+                )
+            })
+            .collect();
+        SharedResults {
+            state: Mutex::new(state),
+        }
+    }
+
+    // number of shared subqueries
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, query: &Query) -> bool {
+        self.state.lock().unwrap().contains_key(query)
+    }
+
+    // number of results in memory
+    pub fn kept(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.values().filter(|shared| shared.cbl.is_some()).count()
+    }
+
+    // the result of a shared subquery if it was already computed
+    pub fn take(&self, query: &Query) -> Option<KmerSet> {
+        let mut state = self.state.lock().unwrap();
+        let shared = state.get_mut(query).filter(|shared| shared.cbl.is_some())?;
+        shared.remaining -= 1;
+        match shared.remaining {
+            0 => shared.cbl.take(),
+            _ => shared.cbl.clone(),
+        }
+    }
+
+    // a use of a subquery that will not be made, e.g. an operand after a conjunction came out
+    // empty: its result is dropped after its last use, and if it is never computed, the uses of
+    // the subqueries it would have computed are not made either
+    pub fn skip(&self, query: &Query) {
+        if matches!(query, Query::Leaf(_)) {
+            return;
+        }
+        let never_computed = {
+            let mut state = self.state.lock().unwrap();
+            match state.get_mut(query) {
+                Some(shared) => {
+                    shared.remaining = shared.remaining.saturating_sub(1);
+                    let computed = shared.cbl.is_some();
+                    if shared.remaining == 0 {
+                        shared.cbl = None;
+                    }
+                    shared.remaining == 0 && !computed
+                }
+                // used once, by this use
+                None => true,
+            }
+        };
+        if never_computed {
+            for operand in evaluated(query) {
+                self.skip(operand);
+            }
+        }
+    }
+
+    // record the result of a shared subquery at its first use, nothing for other queries
+    pub fn keep(&self, query: &Query, cbl: &KmerSet) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(shared) = state.get_mut(query).filter(|shared| shared.cbl.is_none()) {
+            shared.remaining = shared.remaining.saturating_sub(1);
+            if shared.remaining > 0 {
+                shared.cbl = Some(cbl.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::CblCache;
+    use crate::fixtures::{kmers, write_index, W, X, Y, Z};
+    use crate::query::Evaluator;
+    use crate::reduce::Batching;
+    use std::fs;
+
+    fn leaf_and_not(all: &[usize], not: &[usize]) -> Vec<Query> {
+        let mut operands: Vec<Query> = all.iter().map(|&id| Query::Leaf(id)).collect();
+        operands.extend(not.iter().map(|&id| Query::negate(Query::Leaf(id))));
+        operands
+    }
+
+    // the same ALL and NOT-ANY samples, in any order, with or without another operand
+    fn batch() -> Vec<Query> {
+        let first = Query::And(leaf_and_not(&[1, 0], &[3, 2]));
+        let mut operands = leaf_and_not(&[0, 1], &[2, 3]);
+        operands.push(Query::any_of([4, 2]));
+        let third = Query::And(leaf_and_not(&[0], &[2]));
+        vec![first.clone(), Query::And(operands), third, first]
+    }
+
+    #[test]
+    fn test_factor() {
+        let factored = factor(&batch());
+        let all = Query::all_of([0, 1]);
+        let controls = Query::negate(Query::any_of([2, 3]));
+        assert_eq!(factored[0], Query::And(vec![all.clone(), controls.clone()]));
+        assert_eq!(
+            factored[1],
+            Query::And(vec![all.clone(), controls, Query::any_of([2, 4])])
+        );
+        // a single sample and a single negation are left as they are
+        assert_eq!(factored[2], batch()[2]);
+        // the repeated query is computed once, and the groups under it counted once
+        let shared = SharedResults::new(&factored);
+        assert_eq!(shared.len(), 3);
+        assert!(shared.contains(&factored[0]) && shared.contains(&all));
+        assert!(shared.contains(&Query::any_of([2, 3])));
+        assert!(!shared.contains(&Query::any_of([2, 4])));
+        // the negation of a union is the same group as the negations of its operands
+        let union = Query::And(vec![
+            Query::Leaf(0),
+            Query::Leaf(1),
+            Query::negate(Query::any_of([3, 2])),
+        ]);
+        assert_eq!(factor(&[union, batch()[0].clone()])[0], factored[0]);
+        // nothing is shared by a single query
+        assert!(SharedResults::new(&factor(&batch()[1..2])).is_empty());
+    }
+
+    #[test]
+    fn test_eval_shared() {
+        let dir = "test_files/output_batch";
+        write_index(
+            dir,
+            &[vec![X, Y, Z], vec![X, Y, W], vec![Z], vec![W], vec![Y]],
+        );
+        let universe = [0, 1, 2, 3, 4];
+        // nothing is kept in a cache of size 0, its misses count the cbls loaded
+        let (alone, together) = (CblCache::new(0), CblCache::new(0));
+        let evaluator = |cache| {
+            Evaluator::new(dir, 21, &universe, Batching::new(2, 1))
+                .unwrap()
+                .with_cache(cache)
+        };
+        let eval_batch = |queries: &[Query]| {
+            let factored = factor(queries);
+            let shared = SharedResults::new(&factored);
+            for (query, factored) in queries.iter().zip(&factored) {
+                let expected = evaluator(&alone).eval(query).unwrap();
+                let cbl = evaluator(&together)
+                    .with_shared(&shared)
+                    .eval(factored)
+                    .unwrap();
+                assert_eq!(kmers(&cbl), kmers(&expected));
+            }
+            shared
+        };
+        let shared = eval_batch(&batch());
+        assert_eq!(alone.stats().misses, 16);
+        assert_eq!(together.stats().misses, 8);
+        // every shared result is dropped after its last use
+        assert_eq!(shared.kept(), 0);
+        // samples 3 and 4 have no k-mer in common, the second query ends before the NOT-ANY
+        // operand computed by the first one, which is dropped all the same
+        let shared = eval_batch(&[
+            Query::And(leaf_and_not(&[0, 1], &[2, 3])),
+            Query::And(leaf_and_not(&[3, 4], &[2, 3])),
+        ]);
+        assert_eq!(shared.len(), 1);
+        assert_eq!(shared.kept(), 0);
+        let _ = fs::remove_dir_all(dir);
+    }
+}
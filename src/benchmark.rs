@@ -9,7 +9,8 @@ use std::path::Path;
 use std::time::Instant;
 use std::io;
 
-use cbl::CBL;
+use anti_reindeer::kmers::{KmerSet, DEFAULT_K};
+use anti_reindeer::reduce::{reduce, Batching, SetOp};
 
 use serde::Serialize;
 #[derive(Serialize)]
@@ -33,8 +34,7 @@ struct DeserializationData {
     nb_kmers: usize,
 }
 
-type T = u64;
-const K: usize = 21;
+const K: usize = DEFAULT_K;
 
 #[derive(Serialize)]
 struct GlobalIOs {
@@ -43,73 +43,27 @@ struct GlobalIOs {
     nb_kmers: usize,
 }
 
-fn merge_cbls_in_batches(file_paths: &[String], batch_size: usize) -> CBL<K, T> {
-    let mut input_iter = file_paths.chunks(batch_size);
-    let mut global_cbl = if let Some(input_filename_chunk) = input_iter.next() {
-        let mut cbls_chunk: Vec<_> = input_filename_chunk
-            .iter()
-            .map(|input_filename| deserialize_cbl(input_filename))
-            .collect();
-        CBL::<K, T>::merge(cbls_chunk.iter_mut().collect())
-    } else {
+fn cbl_size<P: AsRef<Path>>(path: &P) -> u64 {
+    fs::metadata(path).map_or(0, |metadata| metadata.len())
+}
+
+fn merge_cbls_in_batches(file_paths: &[String], batch_size: usize, threads: usize) -> KmerSet {
+    if file_paths.is_empty() {
         panic!("No CBL files to merge");
-    };
-
-    for input_filename_chunk in input_iter {
-        let mut cbls_chunk: Vec<_> = input_filename_chunk
-            .iter()
-            .map(|input_filename| deserialize_cbl(input_filename))
-            .collect();
-        global_cbl |= &mut CBL::<K, T>::merge(cbls_chunk.iter_mut().collect());
     }
-
-    global_cbl
+    reduce(K, file_paths, Batching::new(batch_size, threads), SetOp::Union, |path| deserialize_cbl(path, K), cbl_size).unwrap()
 }
 
-fn intersect_cbls_in_batches(file_paths: &[String], batch_size: usize) -> CBL<K, T> {
-    let mut input_iter = file_paths.chunks(batch_size);
-    let mut global_cbl = if let Some(input_filename_chunk) = input_iter.next() {
-        let mut cbls_chunk: Vec<_> = input_filename_chunk
-            .iter()
-            .map(|input_filename| deserialize_cbl(input_filename))
-            .collect();
-        CBL::<K, T>::intersect(cbls_chunk.iter_mut().collect())
-    } else {
+fn intersect_cbls_in_batches(file_paths: &[String], batch_size: usize, threads: usize) -> KmerSet {
+    if file_paths.is_empty() {
         panic!("No CBL files to intersect");
-    };
-
-    for input_filename_chunk in input_iter {
-        let mut cbls_chunk: Vec<_> = input_filename_chunk
-            .iter()
-            .map(|input_filename| deserialize_cbl(input_filename))
-            .collect();
-        global_cbl &= &mut CBL::<K, T>::intersect(cbls_chunk.iter_mut().collect());
     }
-
-    global_cbl
+    reduce(K, file_paths, Batching::new(batch_size, threads), SetOp::Intersection, |path| deserialize_cbl(path, K), cbl_size).unwrap()
 }
 
-fn difference_cbls_in_batches(global_cbl: &mut CBL<K, T>, input_filenames: &[String], batch_size: usize) {
-        let mut input_iter = input_filenames.chunks(batch_size);
-        let mut local_cbl = if let Some(input_filename_chunk) = input_iter.next() {
-            let mut cbls_chunk: Vec<_> = input_filename_chunk
-                .iter()
-                .map(|input_filename| deserialize_cbl(input_filename))
-                .collect();
-            CBL::<K, T>::intersect(cbls_chunk.iter_mut().collect())
-        } else {
-            unreachable!()
-        };
-
-        for input_filename_chunk in input_iter {
-            let mut cbls_chunk: Vec<_> = input_filename_chunk
-                .iter()
-                .map(|input_filename| deserialize_cbl(input_filename))
-                .collect();
-            local_cbl &= &mut CBL::<K, T>::intersect(cbls_chunk.iter_mut().collect());
-        }
-
-        *global_cbl -= &mut local_cbl;
+fn difference_cbls_in_batches(global_cbl: &mut KmerSet, input_filenames: &[String], batch_size: usize, threads: usize) {
+    let mut local_cbl = intersect_cbls_in_batches(input_filenames, batch_size, threads);
+    *global_cbl -= &mut local_cbl;
 }
 
 fn write_csv<T: Serialize>(data: &[T], filename: &str) -> io::Result<()> {
@@ -124,13 +78,18 @@ fn write_csv<T: Serialize>(data: &[T], filename: &str) -> io::Result<()> {
 fn main() {
     let args: Vec<String> = env::args().collect();
     if args.len() < 4 {
-        eprintln!("Usage: {} <input_file_list> <mode> <max_batch_size> [deser]", args[0]);
+        eprintln!("Usage: {} <input_file_list> <mode> <max_batch_size> [deser] [threads]", args[0]);
         std::process::exit(1);
     }
     let input_file_list = &args[1];
     let mode = &args[2];
     let max_batch_size: usize = args[3].parse().expect("Max batch size must be a number");
-    let do_deserialize = args.get(4).map_or(false, |arg| arg == "deser");
+    let do_deserialize = args[4..].iter().any(|arg| arg == "deser");
+    // batches reduced at a time, 1 by default
+    let threads: usize = args[4..]
+        .iter()
+        .find(|arg| *arg != "deser")
+        .map_or(1, |arg| arg.parse().expect("Threads must be a number"));
 
     if mode != "union" && mode != "intersection" && mode != "difference" {
         eprintln!("Invalid mode. Use 'union', 'intersection', or 'difference'.");
@@ -150,7 +109,7 @@ fn main() {
     let indices: Vec<usize> = (0..col_nb).collect();
     let mut file_paths: Vec<String> = vec![];
 
-	let mut global_cbl = CBL::<K, T>::new();
+	let mut global_cbl = KmerSet::new(K).unwrap();
 
 
     let mut serialization_data: Vec<SerializationData> = Vec::new();
@@ -159,9 +118,9 @@ fn main() {
         let output_file_path = Path::new(&output_filename);
 
         if !output_file_path.exists() {
-            let cbl = create_cbl_from_fasta(input_filename);
+            let cbl = create_cbl_from_fasta(input_filename, K).unwrap();
             let start_serialize = Instant::now();
-            serialize_cbl(&cbl, &output_filename);
+            serialize_cbl(&cbl, &output_filename).unwrap();
             let duration_serialize = start_serialize.elapsed().as_secs_f64();
             serialization_data.push(SerializationData {
                 filename: input_filename.clone(),
@@ -176,7 +135,7 @@ fn main() {
     }
 
     println!("Calculating total number of kmers...");
-    let total_kmer_cbl = merge_cbls_in_batches(&file_paths, file_paths.len());
+    let total_kmer_cbl = merge_cbls_in_batches(&file_paths, file_paths.len(), threads);
     let total_kmers = total_kmer_cbl.count();
     println!("Total number of kmers: {}", total_kmers);
 
@@ -186,11 +145,11 @@ fn main() {
         println!("Testing batch size {}", batch_size);
         let start = Instant::now();
         let result_cbl = match mode.as_str() {
-            "union" => merge_cbls_in_batches(&file_paths, batch_size),
-            "intersection" => intersect_cbls_in_batches(&file_paths, batch_size),
+            "union" => merge_cbls_in_batches(&file_paths, batch_size, threads),
+            "intersection" => intersect_cbls_in_batches(&file_paths, batch_size, threads),
             "difference" => {
                 let mut global_cbl = total_kmer_cbl.clone();
-                difference_cbls_in_batches(&mut global_cbl, &file_paths, batch_size);
+                difference_cbls_in_batches(&mut global_cbl, &file_paths, batch_size, threads);
                 global_cbl
             }
             _ => unreachable!(),
@@ -214,7 +173,8 @@ fn main() {
             "Time taken for {} of batch size {}: {:?}, input_kmers: {}, output_kmers: {}, input_kmers/sec: {}, output_kmers/sec: {}",
             mode, batch_size, duration, total_kmers, output_kmer_total, input_kmer_per_sec, output_kmer_per_sec
         );
-        global_cbl = result_cbl.clone();
+        // keep the last result for the deserialization benchmark, without a copy
+        global_cbl = result_cbl;
     }
 
     let csv_filename = format!("{}_benchmark.csv", mode);
@@ -228,11 +188,11 @@ fn main() {
         let global_cbl_filename = format!("{}/global.cbl", output_dir);
         
         let start_serialize_global = Instant::now();
-        serialize_cbl(&global_cbl, &global_cbl_filename);
+        serialize_cbl(&global_cbl, &global_cbl_filename).unwrap();
         let duration_serialize_global = start_serialize_global.elapsed().as_secs_f64();
 
         let start_deserialize = Instant::now();
-        let deserialized_cbl = deserialize_cbl(&global_cbl_filename);
+        let deserialized_cbl = deserialize_cbl(&global_cbl_filename, K).unwrap();
         let duration_deserialize = start_deserialize.elapsed().as_secs_f64();
         
         let global_data = vec![GlobalIOs {
@@ -8,19 +8,18 @@ use std::time::Instant;
 use std::path::Path;
 
 
-use cbl::CBL;
+use anti_reindeer::kmers::{KmerSet, DEFAULT_K};
 
-type T = u64;
-const K: usize = 21;
+const K: usize = DEFAULT_K;
 
-fn merge_cbls_in_batches(file_paths: &[String], batch_size: usize) -> CBL<K, T> {
+fn merge_cbls_in_batches(file_paths: &[String], batch_size: usize) -> KmerSet {
     let mut input_iter = file_paths.chunks(batch_size);
     let mut global_cbl = if let Some(input_filename_chunk) = input_iter.next() {
         let mut cbls_chunk: Vec<_> = input_filename_chunk
             .iter()
-            .map(|input_filename| deserialize_cbl(input_filename))
+            .map(|input_filename| deserialize_cbl(input_filename, K).unwrap())
             .collect();
-        CBL::<K, T>::merge(cbls_chunk.iter_mut().collect())
+        KmerSet::merge(K, cbls_chunk.iter_mut().collect()).unwrap()
     } else {
         panic!("No CBL files to merge");
     };
@@ -28,23 +27,23 @@ fn merge_cbls_in_batches(file_paths: &[String], batch_size: usize) -> CBL<K, T>
     for input_filename_chunk in input_iter {
         let mut cbls_chunk: Vec<_> = input_filename_chunk
             .iter()
-            .map(|input_filename| deserialize_cbl(input_filename))
+            .map(|input_filename| deserialize_cbl(input_filename, K).unwrap())
             .collect();
-        global_cbl |= &mut CBL::<K, T>::merge(cbls_chunk.iter_mut().collect());
+        global_cbl |= &mut KmerSet::merge(K, cbls_chunk.iter_mut().collect()).unwrap();
     }
 
     global_cbl
 }
 
 
-fn intersect_cbls_in_batches(file_paths: &[String], batch_size: usize) -> CBL<K, T> {
+fn intersect_cbls_in_batches(file_paths: &[String], batch_size: usize) -> KmerSet {
     let mut input_iter = file_paths.chunks(batch_size);
     let mut global_cbl = if let Some(input_filename_chunk) = input_iter.next() {
         let mut cbls_chunk: Vec<_> = input_filename_chunk
             .iter()
-            .map(|input_filename| deserialize_cbl(input_filename))
+            .map(|input_filename| deserialize_cbl(input_filename, K).unwrap())
             .collect();
-        CBL::<K, T>::intersect(cbls_chunk.iter_mut().collect())
+        KmerSet::intersect(K, cbls_chunk.iter_mut().collect()).unwrap()
     } else {
         panic!("No CBL files to intersect");
     };
@@ -52,9 +51,9 @@ fn intersect_cbls_in_batches(file_paths: &[String], batch_size: usize) -> CBL<K,
     for input_filename_chunk in input_iter {
         let mut cbls_chunk: Vec<_> = input_filename_chunk
             .iter()
-            .map(|input_filename| deserialize_cbl(input_filename))
+            .map(|input_filename| deserialize_cbl(input_filename, K).unwrap())
             .collect();
-        global_cbl |= &mut CBL::<K, T>::merge(cbls_chunk.iter_mut().collect());
+        global_cbl |= &mut KmerSet::merge(K, cbls_chunk.iter_mut().collect()).unwrap();
     }
 
     global_cbl
@@ -94,8 +93,8 @@ fn main(){
 		let output_filename = format!("{}/{}u.cbl", output_dir, indices[i]);
         let output_file_path = Path::new(&output_filename);
         if !output_file_path.exists() {
-            let cbl = create_cbl_from_fasta(input_filename);
-            serialize_cbl(&cbl, &output_filename);
+            let cbl = create_cbl_from_fasta(input_filename, K).unwrap();
+            serialize_cbl(&cbl, &output_filename).unwrap();
             println!("- created CBL for file: {}", input_filename);
         } else {
             println!("CBL already exists for file: {}", input_filename);
@@ -0,0 +1,200 @@
+use crate::error::Result;
+use crate::kmers::KmerSet;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+struct Entry {
+    cbl: Arc<KmerSet>,
+    size: u64,
+    // value of the clock when the entry was last read
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<usize, Entry>,
+    used: u64,
+    // bytes of the copies of cached cbls held by evaluations, counted against the capacity
+    lent: u64,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+// what a cache holds and how often it was read
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct CacheStats {
+    pub cbls: usize,
+    pub bytes: u64,
+    pub lent: u64,
+    pub capacity: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+// deserialized cbls by sample id, the least recently used are dropped to keep them and the copies
+// lent out under capacity bytes, a cbl that does not fit is not kept
+pub struct CblCache {
+    capacity: u64,
+    state: Mutex<State>,
+}
+
+impl CblCache {
+    pub fn new(capacity: u64) -> Self {
+        CblCache {
+            capacity,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    // the cbl of a sample, loaded and kept if it is not cached, size estimates its bytes
+    // the lock is not held while loading, two threads missing the same cbl both load it
+    pub fn get_or_load<F: FnOnce() -> Result<KmerSet>>(
+        &self,
+        id: usize,
+        size: u64,
+        load: F,
+    ) -> Result<Arc<KmerSet>> {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.clock += 1;
+            let clock = state.clock;
+            if let Some(entry) = state.entries.get_mut(&id) {
+                entry.last_used = clock;
+                let cbl = entry.cbl.clone();
+                state.hits += 1;
+                return Ok(cbl);
+            }
+            state.misses += 1;
+        }
+        let cbl = Arc::new(load()?);
+        let mut state = self.state.lock().unwrap();
+        // nothing is dropped for a cbl that would not fit anyway
+        if !state.entries.contains_key(&id) && state.lent + size <= self.capacity {
+            state.make_room(self.capacity - size);
+            state.used += size;
+            let last_used = state.clock;
+            state.entries.insert(
+                id,
+                Entry {
+                    cbl: cbl.clone(),
+                    size,
+                    last_used,
+                },
+            );
+        }
+        Ok(cbl)
+    }
+
+    // a copy of size bytes of a cached cbl is held until it is given back, the least recently
+    // used cbls are dropped to make room for it
+    pub fn lend(&self, size: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.lent += size;
+        state.make_room(self.capacity);
+    }
+
+    pub fn give_back(&self, size: u64) {
+        self.state.lock().unwrap().lent -= size;
+    }
+
+    pub fn contains(&self, id: usize) -> bool {
+        self.state.lock().unwrap().entries.contains_key(&id)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let state = self.state.lock().unwrap();
+        CacheStats {
+            cbls: state.entries.len(),
+            bytes: state.used,
+            lent: state.lent,
+            capacity: self.capacity,
+            hits: state.hits,
+            misses: state.misses,
+        }
+    }
+}
+
+impl State {
+    // drop the least recently used entries until the cached and lent bytes fit in capacity
+    fn make_room(&mut self, capacity: u64) {
+        while self.used + self.lent > capacity && !self.entries.is_empty() {
+            self.evict();
+        }
+    }
+
+    // drop the least recently used entry
+    fn evict(&mut self) {
+        let oldest = self
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(&id, _)| id);
+        if let Some(entry) = oldest.and_then(|id| self.entries.remove(&id)) {
+            self.used -= entry.size;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::GrimrError;
+
+    fn load(count: &mut usize) -> Result<KmerSet> {
+        *count += 1;
+        KmerSet::new(21)
+    }
+
+    #[test]
+    fn test_lru() {
+        let cache = CblCache::new(100);
+        let mut loads = 0;
+        cache.get_or_load(0, 40, || load(&mut loads)).unwrap();
+        cache.get_or_load(1, 40, || load(&mut loads)).unwrap();
+        cache.get_or_load(0, 40, || load(&mut loads)).unwrap();
+        assert_eq!(loads, 2);
+        // 1 is the least recently used
+        cache.get_or_load(2, 40, || load(&mut loads)).unwrap();
+        assert!(cache.contains(0) && !cache.contains(1) && cache.contains(2));
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                cbls: 2,
+                bytes: 80,
+                lent: 0,
+                capacity: 100,
+                hits: 1,
+                misses: 3,
+            }
+        );
+        // too large to be kept, everything else stays
+        cache.get_or_load(3, 200, || load(&mut loads)).unwrap();
+        assert!(!cache.contains(3) && cache.contains(0));
+        cache.get_or_load(4, 100, || load(&mut loads)).unwrap();
+        assert_eq!(cache.stats().cbls, 1);
+        assert!(cache
+            .get_or_load(5, 10, || Err(GrimrError::MissingSample("5".to_string())))
+            .is_err());
+        assert!(!cache.contains(5));
+    }
+    #[test]
+    fn test_lend() {
+        let cache = CblCache::new(100);
+        let mut loads = 0;
+        cache.get_or_load(0, 40, || load(&mut loads)).unwrap();
+        cache.get_or_load(1, 40, || load(&mut loads)).unwrap();
+        // room is made for the copy by dropping 0
+        cache.lend(50);
+        assert!(!cache.contains(0) && cache.contains(1));
+        // 1 is not dropped for a cbl that does not fit beside the copy
+        cache.get_or_load(2, 60, || load(&mut loads)).unwrap();
+        assert!(!cache.contains(2) && cache.contains(1));
+        cache.give_back(50);
+        cache.get_or_load(2, 60, || load(&mut loads)).unwrap();
+        assert!(cache.contains(1) && cache.contains(2));
+        assert_eq!(cache.stats().lent, 0);
+        assert_eq!(cache.stats().bytes, 100);
+    }
+}
@@ -0,0 +1,258 @@
+use crate::error::{GrimrError, Result};
+use crate::input::InputType;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+// one line of the metadata file, `id` is also the number of its serialized CBL
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sample {
+    pub id: usize,
+    pub name: String,
+    pub path: String,
+    #[serde(default)]
+    pub input: InputType,
+    pub tags: Vec<String>,
+    // for the result of a query saved in the index, how it was computed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>,
+}
+
+// the query a derived sample is the result of, and the samples it used
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provenance {
+    pub query: String,
+    pub samples: Vec<usize>,
+}
+
+impl Sample {
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+
+    // the path as written in the metadata file, with the prefix of its input type
+    pub fn entry(&self) -> String {
+        match self.input {
+            InputType::Sequences => self.path.clone(),
+            InputType::Kmers => format!("kmers:{}", self.path),
+            InputType::Derived => format!("query:{}", self.path),
+        }
+    }
+
+    pub fn is_derived(&self) -> bool {
+        self.input == InputType::Derived
+    }
+}
+
+// samples of the metadata file with their paths and tags
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SampleCatalog {
+    pub samples: Vec<Sample>,
+}
+
+// sample name derived from its path: file name without extensions
+fn sample_name(path: &str) -> String {
+    let file_name = Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(path);
+    match file_name.find('.') {
+        Some(0) | None => file_name.to_string(),
+        Some(dot) => file_name[..dot].to_string(),
+    }
+}
+
+impl SampleCatalog {
+    // read the metadata file: a path followed by whitespace separated tags on every line,
+    // a `kmers:` prefix marks the path of a k-mer dump instead of a sequence file
+    // samples whose derived name collides with a previous one are named by their path
+    pub fn from_metadata(file_path: &str) -> Result<Self> {
+        let file = File::open(file_path).map_err(GrimrError::io(file_path))?;
+        let reader = BufReader::new(file);
+        let mut samples = Vec::new();
+        let mut names = HashSet::new();
+
+        for line in reader.lines() {
+            let line = line.map_err(GrimrError::io(file_path))?;
+            let mut columns = line.split_whitespace();
+            if let Some(entry) = columns.next() {
+                let (input, path) = InputType::from_entry(entry);
+                let mut name = sample_name(path);
+                if !names.insert(name.clone()) {
+                    name = path.to_string();
+                    names.insert(name.clone());
+                }
+                samples.push(Sample {
+                    id: samples.len(),
+                    name,
+                    path: path.to_string(),
+                    input,
+                    tags: columns.map(|tag| tag.to_string()).collect(),
+                    provenance: None,
+                });
+            }
+        }
+
+        Ok(SampleCatalog { samples })
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn paths(&self) -> Vec<String> {
+        self.samples.iter().map(|s| s.path.clone()).collect()
+    }
+
+    // ids are kept when samples are removed, so they may not match positions
+    pub fn get(&self, id: usize) -> Option<&Sample> {
+        self.samples.iter().find(|s| s.id == id)
+    }
+
+    // first id above every sample
+    pub fn next_id(&self) -> usize {
+        self.samples.iter().map(|s| s.id + 1).max().unwrap_or(0)
+    }
+
+    // name of a new sample: its file name without extensions, or its path if that name is
+    // already a sample or a tag, or a number queries would take for an id
+    pub fn new_name(&self, path: &str) -> String {
+        let name = sample_name(path);
+        match self.resolve(&name).is_some() || name.parse::<usize>().is_ok() {
+            true => path.to_string(),
+            false => name,
+        }
+    }
+
+    // append a sample named by new_name
+    // as in the metadata file, a `kmers:` prefix marks a k-mer dump
+    pub fn push(&mut self, id: usize, entry: &str, tags: Vec<String>) {
+        let (input, path) = InputType::from_entry(entry);
+        self.samples.push(Sample {
+            id,
+            name: self.new_name(path),
+            path: path.to_string(),
+            input,
+            tags,
+            provenance: None,
+        });
+    }
+
+    // append the result of a query, its path is the query file
+    pub fn push_derived(
+        &mut self,
+        id: usize,
+        name: &str,
+        query_file: &str,
+        provenance: Provenance,
+    ) {
+        self.samples.push(Sample {
+            id,
+            name: name.to_string(),
+            path: query_file.to_string(),
+            input: InputType::Derived,
+            tags: Vec::new(),
+            provenance: Some(provenance),
+        });
+    }
+
+    // samples read from a file, not saved from a query
+    pub fn sources(&self) -> impl Iterator<Item = &Sample> {
+        self.samples.iter().filter(|s| !s.is_derived())
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<Sample> {
+        let position = self.samples.iter().position(|s| s.id == id)?;
+        Some(self.samples.remove(position))
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<&Sample> {
+        self.samples.iter().find(|s| s.name == name)
+    }
+
+    // ids of the samples carrying a tag
+    pub fn by_tag(&self, tag: &str) -> Vec<usize> {
+        self.samples
+            .iter()
+            .filter(|s| s.has_tag(tag))
+            .map(|s| s.id)
+            .collect()
+    }
+
+    // every distinct tag, in order of first appearance
+    pub fn tags(&self) -> Vec<&str> {
+        let mut seen = HashSet::new();
+        self.samples
+            .iter()
+            .flat_map(|s| s.tags.iter())
+            .filter(|tag| seen.insert(tag.as_str()))
+            .map(|tag| tag.as_str())
+            .collect()
+    }
+
+    // samples referred to by a tag, or else by a sample name
+    pub fn resolve(&self, tag_or_name: &str) -> Option<Vec<usize>> {
+        let ids = self.by_tag(tag_or_name);
+        if !ids.is_empty() {
+            return Some(ids);
+        }
+        self.by_name(tag_or_name).map(|s| vec![s.id])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_metadata() {
+        let catalog = SampleCatalog::from_metadata("test_files/metadata.csv").unwrap();
+        assert_eq!(catalog.len(), 6);
+        assert_eq!(catalog.samples[0].name, "test1");
+        assert_eq!(catalog.samples[0].path, "test_files/test1.fa");
+        assert_eq!(catalog.samples[0].tags, vec!["A", "C", "E", "H", "L", "M"]);
+        assert_eq!(catalog.by_tag("A"), vec![0, 1, 2]);
+        assert_eq!(catalog.by_tag("M"), vec![0, 2]);
+        assert!(catalog.by_tag("Z").is_empty());
+        assert_eq!(catalog.by_name("test4").unwrap().id, 3);
+        assert_eq!(catalog.resolve("test5"), Some(vec![4]));
+        assert_eq!(catalog.resolve("D"), Some(vec![1, 3, 5]));
+        assert_eq!(catalog.resolve("nothing"), None);
+        assert_eq!(catalog.tags()[..4], ["A", "C", "E", "H"]);
+    }
+
+    #[test]
+    fn test_push_and_remove() {
+        let mut catalog = SampleCatalog::from_metadata("test_files/metadata.csv").unwrap();
+        let removed = catalog.remove(1).unwrap();
+        assert_eq!(removed.name, "test2");
+        assert!(catalog.get(1).is_none());
+        assert_eq!(catalog.get(2).unwrap().name, "test3");
+        assert_eq!(catalog.next_id(), 6);
+        catalog.push(6, "other/test3.fa", vec!["A".to_string()]);
+        assert_eq!(catalog.get(6).unwrap().name, "other/test3.fa");
+        assert_eq!(catalog.by_tag("A"), vec![0, 2, 6]);
+        catalog.push(7, "kmers:counts/test7.tsv", vec![]);
+        let sample = catalog.get(7).unwrap();
+        assert_eq!(
+            (sample.name.as_str(), sample.path.as_str()),
+            ("test7", "counts/test7.tsv")
+        );
+        assert_eq!(sample.input, InputType::Kmers);
+        assert_eq!(sample.entry(), "kmers:counts/test7.tsv");
+        assert_eq!(catalog.get(6).unwrap().input, InputType::Sequences);
+    }
+
+    #[test]
+    fn test_sample_name() {
+        assert_eq!(sample_name("dir/SRR567013.fastq.gz"), "SRR567013");
+        assert_eq!(sample_name("sample"), "sample");
+        assert_eq!(sample_name(".hidden"), ".hidden");
+    }
+}
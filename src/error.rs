@@ -0,0 +1,106 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// everything that can go wrong in the library, the command line exits with a code per kind
+#[derive(Debug)]
+pub enum GrimrError {
+    // reading or writing a file, with its path when known
+    Io {
+        path: Option<PathBuf>,
+        source: io::Error,
+    },
+    // a malformed metadata file, label file, cbl or manifest
+    Parse(String),
+    // an index written by another version, with another k or another integer type
+    FormatVersion(String),
+    // a sample or a cbl the index does not have
+    MissingSample(String),
+    // a query that cannot be evaluated, such as an unknown tag
+    InvalidQuery(String),
+    // a command line argument out of range, such as an unsupported k
+    InvalidArgument(String),
+}
+
+pub type Result<T> = std::result::Result<T, GrimrError>;
+
+impl GrimrError {
+    // to use with map_err on the result of an operation on path
+    pub fn io<P: AsRef<Path>>(path: P) -> impl FnOnce(io::Error) -> GrimrError {
+        let path = path.as_ref().to_path_buf();
+        move |source| GrimrError::Io {
+            path: Some(path),
+            source,
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            GrimrError::InvalidArgument(_) => 1,
+            GrimrError::Io { .. } => 2,
+            GrimrError::Parse(_) => 3,
+            GrimrError::FormatVersion(_) => 4,
+            GrimrError::MissingSample(_) => 5,
+            GrimrError::InvalidQuery(_) => 6,
+        }
+    }
+}
+
+impl fmt::Display for GrimrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GrimrError::Io {
+                path: Some(path),
+                source,
+            } => write!(f, "{}: {}", path.display(), source),
+            GrimrError::Io { path: None, source } => write!(f, "{}", source),
+            GrimrError::Parse(message) => write!(f, "parse error: {}", message),
+            GrimrError::FormatVersion(message) => write!(f, "incompatible index: {}", message),
+            GrimrError::MissingSample(message) => write!(f, "missing sample: {}", message),
+            GrimrError::InvalidQuery(message) => write!(f, "invalid query: {}", message),
+            GrimrError::InvalidArgument(message) => write!(f, "invalid argument: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for GrimrError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GrimrError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for GrimrError {
+    fn from(source: io::Error) -> Self {
+        GrimrError::Io { path: None, source }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_and_exit_codes() {
+        let missing =
+            std::fs::File::open("test_files/none.fa").map_err(GrimrError::io("test_files/none.fa"));
+        let error = missing.unwrap_err();
+        assert!(error.to_string().starts_with("test_files/none.fa: "));
+        assert_eq!(error.exit_code(), 2);
+        let errors = [
+            GrimrError::InvalidArgument(String::new()),
+            GrimrError::Parse(String::new()),
+            GrimrError::FormatVersion(String::new()),
+            GrimrError::MissingSample(String::new()),
+            GrimrError::InvalidQuery(String::new()),
+        ];
+        let mut codes: Vec<i32> = errors.iter().map(|e| e.exit_code()).collect();
+        codes.push(2);
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), 6);
+        assert!(codes.iter().all(|&code| code != 0));
+    }
+}
@@ -0,0 +1,436 @@
+use crate::catalog::SampleCatalog;
+use crate::error::{GrimrError, Result};
+use crate::query::Query;
+
+// tag expression over the metadata columns, e.g. `(liver AND tumor) AND NOT (control OR mouse)`
+// a bare tag reads as ANY(tag): present in at least one sample carrying the tag
+// a sample name can be used wherever a tag is expected
+// `ATLEAST(80%, tumor)`, `ATMOST(1, control)` and `BETWEEN(2, 3, liver)` bound the number
+// of samples of a tag containing the k-mer
+#[derive(Debug, Clone, PartialEq)]
+pub enum TagExpr {
+    All(String),
+    Any(String),
+    Quorum {
+        min: Option<Threshold>,
+        max: Option<Threshold>,
+        tag: String,
+    },
+    Not(Box<TagExpr>),
+    And(Vec<TagExpr>),
+    Or(Vec<TagExpr>),
+}
+
+// a number of samples, or a percentage of the samples carrying the tag
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Threshold {
+    Count(usize),
+    Percent(f64),
+}
+
+impl Threshold {
+    // percentages round towards the inside of the range
+    fn resolve(self, n: usize, round_up: bool) -> usize {
+        match self {
+            Threshold::Count(count) => count,
+            Threshold::Percent(p) if round_up => (p * n as f64 / 100.0 - 1e-9).ceil() as usize,
+            Threshold::Percent(p) => (p * n as f64 / 100.0 + 1e-9).floor() as usize,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    Comma,
+    And,
+    Or,
+    Not,
+    All,
+    Any,
+    AtLeast,
+    AtMost,
+    Between,
+    Ident(String),
+}
+
+fn invalid(message: String) -> GrimrError {
+    GrimrError::InvalidQuery(message)
+}
+
+fn keyword_or_ident(word: &str) -> Token {
+    match word.to_ascii_uppercase().as_str() {
+        "AND" => Token::And,
+        "OR" => Token::Or,
+        "NOT" => Token::Not,
+        "ALL" => Token::All,
+        "ANY" => Token::Any,
+        "ATLEAST" => Token::AtLeast,
+        "ATMOST" => Token::AtMost,
+        "BETWEEN" => Token::Between,
+        _ => Token::Ident(word.to_string()),
+    }
+}
+
+fn tokenize(input: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    for c in input.chars() {
+        if c == '(' || c == ')' || c == ',' || c.is_whitespace() {
+            if !word.is_empty() {
+                tokens.push(keyword_or_ident(&word));
+                word.clear();
+            }
+            if c == '(' {
+                tokens.push(Token::LParen);
+            } else if c == ')' {
+                tokens.push(Token::RParen);
+            } else if c == ',' {
+                tokens.push(Token::Comma);
+            }
+        } else {
+            word.push(c);
+        }
+    }
+    if !word.is_empty() {
+        tokens.push(keyword_or_ident(&word));
+    }
+    tokens
+}
+
+// recursive descent, NOT binds tighter than AND, which binds tighter than OR
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<()> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            other => Err(invalid(format!(
+                "expected {:?} in tag expression, found {:?}",
+                expected, other
+            ))),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<TagExpr> {
+        let mut terms = vec![self.parse_and()?];
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            terms.push(self.parse_and()?);
+        }
+        Ok(if terms.len() == 1 {
+            terms.pop().unwrap()
+        } else {
+            TagExpr::Or(terms)
+        })
+    }
+
+    fn parse_and(&mut self) -> Result<TagExpr> {
+        let mut factors = vec![self.parse_not()?];
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            factors.push(self.parse_not()?);
+        }
+        Ok(if factors.len() == 1 {
+            factors.pop().unwrap()
+        } else {
+            TagExpr::And(factors)
+        })
+    }
+
+    fn parse_not(&mut self) -> Result<TagExpr> {
+        match self.next() {
+            Some(Token::Not) => Ok(TagExpr::Not(Box::new(self.parse_not()?))),
+            Some(Token::LParen) => {
+                let expr = self.parse_or()?;
+                self.expect(Token::RParen)?;
+                Ok(expr)
+            }
+            Some(Token::All) => Ok(TagExpr::All(self.parse_quantified()?)),
+            Some(Token::Any) => Ok(TagExpr::Any(self.parse_quantified()?)),
+            Some(Token::AtLeast) => self.parse_quorum(true, false),
+            Some(Token::AtMost) => self.parse_quorum(false, true),
+            Some(Token::Between) => self.parse_quorum(true, true),
+            Some(Token::Ident(tag)) => Ok(TagExpr::Any(tag)),
+            other => Err(invalid(format!("unexpected {:?} in tag expression", other))),
+        }
+    }
+
+    // `ALL(tag)` or `ANY(tag)`
+    fn parse_quantified(&mut self) -> Result<String> {
+        self.expect(Token::LParen)?;
+        let tag = self.parse_tag()?;
+        self.expect(Token::RParen)?;
+        Ok(tag)
+    }
+
+    // `ATLEAST(min, tag)`, `ATMOST(max, tag)` or `BETWEEN(min, max, tag)`
+    fn parse_quorum(&mut self, has_min: bool, has_max: bool) -> Result<TagExpr> {
+        self.expect(Token::LParen)?;
+        let bound = |parser: &mut Self, present: bool| -> Result<Option<Threshold>> {
+            if !present {
+                return Ok(None);
+            }
+            let threshold = parser.parse_threshold()?;
+            parser.expect(Token::Comma)?;
+            Ok(Some(threshold))
+        };
+        let min = bound(self, has_min)?;
+        let max = bound(self, has_max)?;
+        let tag = self.parse_tag()?;
+        self.expect(Token::RParen)?;
+        Ok(TagExpr::Quorum { min, max, tag })
+    }
+
+    fn parse_tag(&mut self) -> Result<String> {
+        match self.next() {
+            Some(Token::Ident(tag)) => Ok(tag),
+            other => Err(invalid(format!(
+                "expected a tag in tag expression, found {:?}",
+                other
+            ))),
+        }
+    }
+
+    // `3` or `80%`
+    fn parse_threshold(&mut self) -> Result<Threshold> {
+        let word = match self.next() {
+            Some(Token::Ident(word)) => word,
+            other => {
+                return Err(invalid(format!(
+                    "expected a sample count in tag expression, found {:?}",
+                    other
+                )))
+            }
+        };
+        let threshold = match word.strip_suffix('%') {
+            Some(percent) => percent
+                .parse::<f64>()
+                .ok()
+                .filter(|p| (0.0..=100.0).contains(p))
+                .map(Threshold::Percent),
+            None => word.parse::<usize>().ok().map(Threshold::Count),
+        };
+        threshold.ok_or_else(|| invalid(format!("invalid sample count '{}'", word)))
+    }
+}
+
+// parse a tag expression, lines starting with '#' are comments
+pub fn parse_tag_expr(input: &str) -> Result<TagExpr> {
+    let source = input
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let mut parser = Parser {
+        tokens: tokenize(&source),
+        pos: 0,
+    };
+    if parser.tokens.is_empty() {
+        return Err(invalid("empty tag expression".to_string()));
+    }
+    let expr = parser.parse_or()?;
+    if parser.pos < parser.tokens.len() {
+        return Err(invalid(format!(
+            "unexpected {:?} after the end of the tag expression",
+            parser.tokens[parser.pos]
+        )));
+    }
+    Ok(expr)
+}
+
+fn resolve(catalog: &SampleCatalog, tag: &str) -> Result<Vec<usize>> {
+    catalog
+        .resolve(tag)
+        .ok_or_else(|| invalid(format!("unknown tag or sample '{}'", tag)))
+}
+
+// turn a tag expression into a query over the samples of the catalog
+pub fn to_query(expr: &TagExpr, catalog: &SampleCatalog) -> Result<Query> {
+    Ok(match expr {
+        TagExpr::All(tag) => Query::all_of(resolve(catalog, tag)?),
+        TagExpr::Any(tag) => Query::any_of(resolve(catalog, tag)?),
+        TagExpr::Quorum { min, max, tag } => {
+            let samples = resolve(catalog, tag)?;
+            let n = samples.len();
+            let min = min.map_or(0, |t| t.resolve(n, true));
+            let max = max.map(|t| t.resolve(n, false)).filter(|&max| max < n);
+            if max.is_some_and(|max| max < min) {
+                return Err(invalid(format!(
+                    "empty sample count range for '{}' ({} samples)",
+                    tag, n
+                )));
+            }
+            Query::Quorum { min, max, samples }
+        }
+        TagExpr::Not(inner) => Query::negate(to_query(inner, catalog)?),
+        TagExpr::And(exprs) => Query::And(
+            exprs
+                .iter()
+                .map(|e| to_query(e, catalog))
+                .collect::<Result<_>>()?,
+        ),
+        TagExpr::Or(exprs) => Query::Or(
+            exprs
+                .iter()
+                .map(|e| to_query(e, catalog))
+                .collect::<Result<_>>()?,
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::Sample;
+
+    fn tags() -> SampleCatalog {
+        let tags: [&[&str]; 7] = [
+            &["liver"],
+            &["liver", "tumor"],
+            &["liver", "tumor"],
+            &["tumor"],
+            &["control"],
+            &["mouse"],
+            &["mouse"],
+        ];
+        SampleCatalog {
+            samples: tags
+                .iter()
+                .enumerate()
+                .map(|(id, sample_tags)| Sample {
+                    id,
+                    name: format!("s{}", id),
+                    path: format!("s{}.fa", id),
+                    input: Default::default(),
+                    tags: sample_tags.iter().map(|tag| tag.to_string()).collect(),
+                    provenance: None,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_parse_precedence() {
+        let expr = parse_tag_expr("a OR b AND NOT c").unwrap();
+        assert_eq!(
+            expr,
+            TagExpr::Or(vec![
+                TagExpr::Any("a".to_string()),
+                TagExpr::And(vec![
+                    TagExpr::Any("b".to_string()),
+                    TagExpr::Not(Box::new(TagExpr::Any("c".to_string()))),
+                ]),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse_tag_expr("").is_err());
+        assert!(parse_tag_expr("(a AND b").is_err());
+        assert!(parse_tag_expr("a b").is_err());
+        assert!(parse_tag_expr("ALL(a AND b)").is_err());
+        assert!(parse_tag_expr("ATLEAST(a)").is_err());
+        assert!(parse_tag_expr("ATLEAST(120%, a)").is_err());
+        assert!(parse_tag_expr("BETWEEN(1, a)").is_err());
+    }
+
+    #[test]
+    fn test_parse_quorum() {
+        let expr = parse_tag_expr("ATLEAST(80%, tumor) AND atmost(1,control)").unwrap();
+        assert_eq!(
+            expr,
+            TagExpr::And(vec![
+                TagExpr::Quorum {
+                    min: Some(Threshold::Percent(80.0)),
+                    max: None,
+                    tag: "tumor".to_string(),
+                },
+                TagExpr::Quorum {
+                    min: None,
+                    max: Some(Threshold::Count(1)),
+                    tag: "control".to_string(),
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_to_query() {
+        let expr = parse_tag_expr("(ALL(liver) AND tumor) AND NOT (control OR mouse)").unwrap();
+        assert_eq!(
+            to_query(&expr, &tags()).unwrap(),
+            Query::And(vec![
+                Query::And(vec![Query::all_of([0, 1, 2]), Query::any_of([1, 2, 3])]),
+                Query::negate(Query::Or(vec![Query::Leaf(4), Query::any_of([5, 6])])),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_to_query_nested() {
+        // not expressible with the four label sets
+        let expr = parse_tag_expr("ALL(liver) OR (s3 AND NOT mouse)").unwrap();
+        assert_eq!(
+            to_query(&expr, &tags()).unwrap(),
+            Query::Or(vec![
+                Query::all_of([0, 1, 2]),
+                Query::And(vec![Query::Leaf(3), Query::negate(Query::any_of([5, 6]))]),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_to_query_quorum() {
+        let catalog = tags();
+        let quorum = |input: &str| to_query(&parse_tag_expr(input).unwrap(), &catalog);
+        // 2 of the 3 tumor samples is 66%, rounded up
+        assert_eq!(
+            quorum("ATLEAST(60%, tumor)").unwrap(),
+            Query::Quorum {
+                min: 2,
+                max: None,
+                samples: vec![1, 2, 3],
+            }
+        );
+        assert_eq!(
+            quorum("BETWEEN(1, 50%, liver)").unwrap(),
+            Query::Quorum {
+                min: 1,
+                max: Some(1),
+                samples: vec![0, 1, 2],
+            }
+        );
+        // a maximum covering every sample is no bound
+        assert_eq!(
+            quorum("ATMOST(2, mouse)").unwrap(),
+            Query::Quorum {
+                min: 0,
+                max: None,
+                samples: vec![5, 6],
+            }
+        );
+        assert!(quorum("BETWEEN(3, 1, liver)").is_err());
+    }
+
+    #[test]
+    fn test_to_query_unknown() {
+        let expr = parse_tag_expr("liver AND unknown").unwrap();
+        assert!(to_query(&expr, &tags()).is_err());
+    }
+}
@@ -0,0 +1,37 @@
+// k-mers and samples shared by the tests of the library
+use crate::kmers::KmerSet;
+use crate::utils::serialize_cbl;
+use std::collections::BTreeSet;
+use std::fs;
+
+pub const K: usize = 21;
+
+// four k-mers, each its own canonical form
+pub const X: &str = "AAAAAAAAAAAAAAAAAAAAA";
+pub const Y: &str = "CCCCCCCCCCCCCCCCCCCCA";
+pub const Z: &str = "AAAAAAAAAACAAAAAAAAAA";
+pub const W: &str = "ACACACACACACACACACACA";
+
+// the k-mers of the sequences
+pub fn set_of(seqs: &[&str]) -> KmerSet {
+    let mut cbl = KmerSet::new(K).unwrap();
+    for seq in seqs {
+        cbl.insert_seq(seq.as_bytes());
+    }
+    cbl
+}
+
+// the k-mers of a set, sorted to compare sets
+pub fn kmers(cbl: &KmerSet) -> BTreeSet<String> {
+    cbl.kmers()
+        .map(|kmer| String::from_utf8(kmer).unwrap())
+        .collect()
+}
+
+// one cbl per sample in dir, the k-mers of samples[i] in i.cbl
+pub fn write_index(dir: &str, samples: &[Vec<&str>]) {
+    fs::create_dir_all(dir).unwrap();
+    for (id, seqs) in samples.iter().enumerate() {
+        serialize_cbl(&set_of(seqs), &format!("{}/{}.cbl", dir, id)).unwrap();
+    }
+}
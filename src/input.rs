@@ -0,0 +1,296 @@
+use crate::error::{GrimrError, Result};
+use crate::kmers::KmerSet;
+use needletail::parse_fastx_reader;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+// what the file of a sample holds, declared in the metadata file by a prefix of its path
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum InputType {
+    // fasta or fastq reads or assemblies
+    #[default]
+    Sequences,
+    // a k-mer per line, optionally followed by its count, `kmers:` prefix
+    Kmers,
+    // the result of a query saved in the index, it has no file to be built from
+    Derived,
+}
+
+impl InputType {
+    // the input type and the path of a metadata file entry
+    pub fn from_entry(entry: &str) -> (InputType, &str) {
+        match entry.strip_prefix("kmers:") {
+            Some(path) => (InputType::Kmers, path),
+            None => (InputType::Sequences, entry),
+        }
+    }
+}
+
+// k-mers kept when building a cbl from a sequence file, recorded in the index manifest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InputFilter {
+    // bases with a lower phred score end the k-mers around them, fastq only
+    pub min_quality: Option<u8>,
+    // k-mers seen fewer times in the sample are dropped, 1 keeps all of them
+    pub min_abundance: usize,
+}
+
+impl Default for InputFilter {
+    fn default() -> Self {
+        InputFilter {
+            min_quality: None,
+            min_abundance: 1,
+        }
+    }
+}
+
+impl fmt::Display for InputFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.min_quality {
+            Some(min_quality) => write!(f, "minimum base quality {}", min_quality)?,
+            None => write!(f, "no minimum base quality")?,
+        }
+        write!(f, ", minimum k-mer abundance {}", self.min_abundance)
+    }
+}
+
+impl InputFilter {
+    pub fn is_default(&self) -> bool {
+        *self == InputFilter::default()
+    }
+
+    // parts of a read made of bases of sufficient quality, bases without a score are left out
+    fn segments<'s>(&self, seq: &'s [u8], qual: Option<&[u8]>) -> Vec<&'s [u8]> {
+        match (self.min_quality, qual) {
+            (Some(min_quality), Some(qual)) => {
+                let mut segments = Vec::new();
+                let mut start = 0;
+                for (i, (_, score)) in seq.iter().zip(qual).enumerate() {
+                    if score.saturating_sub(33) < min_quality {
+                        segments.push(&seq[start..i]);
+                        start = i + 1;
+                    }
+                }
+                segments.push(&seq[start..seq.len().min(qual.len())]);
+                segments
+            }
+            _ => vec![seq],
+        }
+    }
+}
+
+// k-mers of a fasta or fastq file, plain or compressed with gzip, bzip2, xz or zstd
+pub fn create_cbl_from_reads(
+    input_filename: &str,
+    k: usize,
+    filter: &InputFilter,
+) -> Result<KmerSet> {
+    let parse_error =
+        |e: needletail::errors::ParseError| GrimrError::Parse(format!("{}: {}", input_filename, e));
+    let file = File::open(input_filename).map_err(GrimrError::io(input_filename))?;
+    let mut reader = parse_fastx_reader(file).map_err(parse_error)?;
+    // layers[i] holds the k-mers seen at least i + 1 times so far
+    let mut layers: Vec<KmerSet> = (0..filter.min_abundance.max(1))
+        .map(|_| KmerSet::new(k))
+        .collect::<Result<_>>()?;
+    while let Some(record) = reader.next() {
+        let seqrec = record.map_err(parse_error)?;
+        let seq = seqrec.seq();
+        for segment in filter.segments(&seq, seqrec.qual()) {
+            if layers.len() == 1 {
+                layers[0].insert_seq(segment);
+            } else {
+                for kmer in segment.windows(k) {
+                    count_kmer(&mut layers, kmer);
+                }
+            }
+        }
+    }
+    Ok(layers.pop().unwrap())
+}
+
+// k-mers of a text dump, `KMER<TAB>COUNT` as written by `jellyfish dump -c -t` or
+// `kmc_dump`, or plain k-mers; k-mers counted less than min_abundance are dropped
+pub fn create_cbl_from_kmer_counts(
+    input_filename: &str,
+    k: usize,
+    filter: &InputFilter,
+) -> Result<KmerSet> {
+    let mut cbl = KmerSet::new(k)?;
+    let file = File::open(input_filename).map_err(GrimrError::io(input_filename))?;
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(GrimrError::io(input_filename))?;
+        let parse_error = |message: String| {
+            GrimrError::Parse(format!("{}:{}: {}", input_filename, number + 1, message))
+        };
+        let mut columns = line.split_whitespace();
+        let kmer = match columns.next() {
+            Some(kmer) => kmer,
+            None => continue,
+        };
+        if kmer.len() != k {
+            return Err(parse_error(format!(
+                "k-mer of length {}, the index uses k = {}",
+                kmer.len(),
+                k
+            )));
+        }
+        // a k-mer without a count is kept whatever the minimum abundance
+        let count = match columns.next() {
+            Some(count) => count
+                .parse::<usize>()
+                .map_err(|_| parse_error(format!("invalid count '{}'", count)))?,
+            None => usize::MAX,
+        };
+        if count >= filter.min_abundance {
+            cbl.insert_seq(kmer.as_bytes());
+        }
+    }
+    Ok(cbl)
+}
+
+// k-mers of the file of a sample, read according to its input type
+pub fn create_cbl_from_input(
+    input_filename: &str,
+    input_type: InputType,
+    k: usize,
+    filter: &InputFilter,
+) -> Result<KmerSet> {
+    match input_type {
+        InputType::Sequences => create_cbl_from_reads(input_filename, k, filter),
+        InputType::Kmers => create_cbl_from_kmer_counts(input_filename, k, filter),
+        InputType::Derived => Err(GrimrError::MissingSample(format!(
+            "the result of query '{}' was saved in the index, run the query again to build it",
+            input_filename
+        ))),
+    }
+}
+
+// one more occurrence of a k-mer, it moves to the next layer until the last one
+fn count_kmer(layers: &mut [KmerSet], kmer: &[u8]) {
+    let seen = layers
+        .iter()
+        .rposition(|layer| layer.contains_seq(kmer).first() == Some(&true))
+        .map_or(0, |i| i + 1);
+    if seen < layers.len() {
+        layers[seen].insert_seq(kmer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::K;
+
+    fn build(path: &str, min_quality: Option<u8>, min_abundance: usize) -> KmerSet {
+        let filter = InputFilter {
+            min_quality,
+            min_abundance,
+        };
+        create_cbl_from_reads(path, K, &filter).unwrap()
+    }
+
+    #[test]
+    fn test_compressed_inputs() {
+        let plain: Vec<Vec<u8>> = build("test_files/reads.fq", None, 1).kmers().collect();
+        // 10 k-mers in r1 and r2, 10 in r3, 5 in r4
+        assert_eq!(plain.len(), 25);
+        for extension in ["gz", "bz2", "xz", "zst"] {
+            let path = format!("test_files/reads.fq.{}", extension);
+            assert_eq!(build(&path, None, 1).kmers().collect::<Vec<_>>(), plain);
+        }
+        assert!(matches!(
+            create_cbl_from_reads("test_files/none.fq.gz", K, &InputFilter::default()),
+            Err(GrimrError::Io { .. })
+        ));
+    }
+
+    #[test]
+    fn test_segments() {
+        let filter = InputFilter {
+            min_quality: Some(20),
+            min_abundance: 1,
+        };
+        // '5' is a score of 20, '4' of 19
+        let seq = b"ACGTACGT";
+        assert_eq!(
+            filter.segments(seq, Some(b"55455555")),
+            vec![&b"AC"[..], b"TACGT"]
+        );
+        assert_eq!(
+            filter.segments(seq, Some(b"45555554")),
+            vec![&b""[..], b"CGTACG", b""]
+        );
+        // the last bases have no score
+        assert_eq!(filter.segments(seq, Some(b"55555")), vec![&b"ACGTA"[..]]);
+        assert_eq!(filter.segments(seq, None), vec![&seq[..]]);
+        assert_eq!(
+            InputFilter::default().segments(seq, Some(b"4")),
+            vec![&seq[..]]
+        );
+    }
+
+    #[test]
+    fn test_kmer_counts() {
+        let filter = |min_abundance| InputFilter {
+            min_quality: None,
+            min_abundance,
+        };
+        let kmers = |cbl: KmerSet| cbl.kmers().collect::<Vec<_>>();
+        // the counts of the 21-mers of reads.fq, and its 21-mers seen twice
+        for min_abundance in [1, 2] {
+            let reads = build("test_files/reads.fq", None, min_abundance);
+            let counts = create_cbl_from_kmer_counts(
+                "test_files/reads_counts.tsv",
+                K,
+                &filter(min_abundance),
+            );
+            assert_eq!(kmers(counts.unwrap()), kmers(reads));
+        }
+        let plain = create_cbl_from_input(
+            "test_files/reads_kmers.txt",
+            InputType::Kmers,
+            K,
+            &filter(5),
+        );
+        // without counts, the minimum abundance does not apply
+        assert_eq!(
+            kmers(plain.unwrap()),
+            kmers(build("test_files/reads.fq", None, 2))
+        );
+        assert!(
+            create_cbl_from_kmer_counts("test_files/reads_counts.tsv", 31, &filter(1)).is_err()
+        );
+        // a fasta file is not a k-mer dump
+        assert!(matches!(
+            create_cbl_from_kmer_counts("test_files/test1.fa", K, &filter(1)),
+            Err(GrimrError::Parse(_))
+        ));
+        assert_eq!(
+            InputType::from_entry("kmers:counts/a.tsv"),
+            (InputType::Kmers, "counts/a.tsv")
+        );
+        assert_eq!(
+            InputType::from_entry("a.fa"),
+            (InputType::Sequences, "a.fa")
+        );
+    }
+
+    #[test]
+    fn test_filters() {
+        // a base of quality 2 in the middle of r3 leaves no 21-mer
+        assert_eq!(build("test_files/reads.fq", Some(20), 1).count(), 15);
+        assert_eq!(build("test_files/reads.fq", Some(2), 1).count(), 25);
+        // only the k-mers of r1 are seen twice
+        assert_eq!(build("test_files/reads.fq.gz", None, 2).count(), 10);
+        assert!(build("test_files/reads.fq.gz", None, 3).is_empty());
+        assert_eq!(build("test_files/reads.fq.gz", Some(20), 2).count(), 10);
+        // fasta records have no quality to filter on
+        let fasta = build("test_files/test1.fa", Some(40), 1);
+        assert_eq!(fasta.count(), build("test_files/test1.fa", None, 1).count());
+    }
+}
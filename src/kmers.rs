@@ -0,0 +1,279 @@
+use crate::error::{GrimrError, Result};
+use bincode::{DefaultOptions, Options};
+use cbl::kmer::Kmer;
+use cbl::CBL;
+use std::io::{Read, Write};
+use std::ops::{BitAnd, BitAndAssign, BitOrAssign, SubAssign};
+
+// k used when none is given
+pub const DEFAULT_K: usize = 21;
+
+fn options() -> impl Options {
+    DefaultOptions::new()
+        .with_varint_encoding()
+        .reject_trailing_bytes()
+}
+
+fn unsupported_k(k: usize) -> GrimrError {
+    GrimrError::InvalidArgument(format!(
+        "unsupported k = {}, supported values: {:?}",
+        k, SUPPORTED_K
+    ))
+}
+
+fn mismatched_k(k: usize, other: usize) -> ! {
+    panic!("cannot combine k-mer sets with k = {} and k = {}", k, other)
+}
+
+// one monomorphized CBL per supported k, u64 holds k-mers up to k = 32, u128 beyond
+macro_rules! kmer_sets {
+    ($($variant:ident($k:literal, $t:ty)),* $(,)?) => {
+        // a CBL whose k is chosen at runtime
+        #[derive(Clone)]
+        pub enum KmerSet {
+            $($variant(CBL<$k, $t>),)*
+        }
+
+        pub const SUPPORTED_K: &[usize] = &[$($k),*];
+
+        impl KmerSet {
+            pub fn new(k: usize) -> Result<Self> {
+                match k {
+                    $($k => Ok(KmerSet::$variant(CBL::new())),)*
+                    _ => Err(unsupported_k(k)),
+                }
+            }
+
+            pub fn k(&self) -> usize {
+                match self {
+                    $(KmerSet::$variant(_) => $k,)*
+                }
+            }
+
+            // integer type the k-mers are encoded in
+            pub fn int_type(&self) -> &'static str {
+                match self {
+                    $(KmerSet::$variant(_) => stringify!($t),)*
+                }
+            }
+
+            pub fn insert_seq(&mut self, seq: &[u8]) {
+                match self {
+                    $(KmerSet::$variant(cbl) => cbl.insert_seq(seq),)*
+                }
+            }
+
+            pub fn contains_seq(&self, seq: &[u8]) -> Vec<bool> {
+                match self {
+                    $(KmerSet::$variant(cbl) => cbl.contains_seq(seq),)*
+                }
+            }
+
+            pub fn count(&self) -> usize {
+                match self {
+                    $(KmerSet::$variant(cbl) => cbl.count(),)*
+                }
+            }
+
+            pub fn is_empty(&self) -> bool {
+                match self {
+                    $(KmerSet::$variant(cbl) => cbl.is_empty(),)*
+                }
+            }
+
+            // nucleotides of every k-mer
+            pub fn kmers(&self) -> Box<dyn Iterator<Item = Vec<u8>> + '_> {
+                match self {
+                    $(KmerSet::$variant(cbl) => Box::new(cbl.iter().map(|kmer| kmer.to_nucs())),)*
+                }
+            }
+
+            // union of sets sharing the same k, empty if there is none
+            pub fn merge(k: usize, sets: Vec<&mut KmerSet>) -> Result<Self> {
+                match k {
+                    $($k => Ok(KmerSet::$variant(CBL::merge(
+                        sets.into_iter()
+                            .map(|set| match set {
+                                KmerSet::$variant(cbl) => cbl,
+                                other => mismatched_k(k, other.k()),
+                            })
+                            .collect(),
+                    ))),)*
+                    _ => Err(unsupported_k(k)),
+                }
+            }
+
+            // intersection of sets sharing the same k, empty if there is none
+            pub fn intersect(k: usize, sets: Vec<&mut KmerSet>) -> Result<Self> {
+                match k {
+                    $($k => Ok(KmerSet::$variant(CBL::intersect(
+                        sets.into_iter()
+                            .map(|set| match set {
+                                KmerSet::$variant(cbl) => cbl,
+                                other => mismatched_k(k, other.k()),
+                            })
+                            .collect(),
+                    ))),)*
+                    _ => Err(unsupported_k(k)),
+                }
+            }
+
+            // only the CBL is encoded, the k is recorded by the index
+            pub fn serialize_into<W: Write>(&self, writer: W) -> Result<()> {
+                match self {
+                    $(KmerSet::$variant(cbl) => options().serialize_into(writer, cbl),)*
+                }
+                .map_err(|e| match *e {
+                    bincode::ErrorKind::Io(e) => GrimrError::from(e),
+                    e => GrimrError::Parse(e.to_string()),
+                })
+            }
+
+            pub fn deserialize_from<R: Read>(k: usize, reader: R) -> Result<Self> {
+                match k {
+                    $($k => options()
+                        .deserialize_from(reader)
+                        .map(KmerSet::$variant)
+                        .map_err(|e| GrimrError::Parse(e.to_string())),)*
+                    _ => Err(unsupported_k(k)),
+                }
+            }
+        }
+
+        impl BitOrAssign<&mut KmerSet> for KmerSet {
+            fn bitor_assign(&mut self, other: &mut KmerSet) {
+                match (self, other) {
+                    $((KmerSet::$variant(a), KmerSet::$variant(b)) => *a |= b,)*
+                    (a, b) => mismatched_k(a.k(), b.k()),
+                }
+            }
+        }
+
+        impl BitAndAssign<&mut KmerSet> for KmerSet {
+            fn bitand_assign(&mut self, other: &mut KmerSet) {
+                match (self, other) {
+                    $((KmerSet::$variant(a), KmerSet::$variant(b)) => *a &= b,)*
+                    (a, b) => mismatched_k(a.k(), b.k()),
+                }
+            }
+        }
+
+        impl SubAssign<&mut KmerSet> for KmerSet {
+            fn sub_assign(&mut self, other: &mut KmerSet) {
+                match (self, other) {
+                    $((KmerSet::$variant(a), KmerSet::$variant(b)) => *a -= b,)*
+                    (a, b) => mismatched_k(a.k(), b.k()),
+                }
+            }
+        }
+
+        impl BitAnd<&mut KmerSet> for &mut KmerSet {
+            type Output = KmerSet;
+
+            fn bitand(self, other: &mut KmerSet) -> KmerSet {
+                match (self, other) {
+                    $((KmerSet::$variant(a), KmerSet::$variant(b)) => KmerSet::$variant(a & b),)*
+                    (a, b) => mismatched_k(a.k(), b.k()),
+                }
+            }
+        }
+    };
+}
+
+kmer_sets! {
+    K15(15, u64),
+    K17(17, u64),
+    K19(19, u64),
+    K21(21, u64),
+    K23(23, u64),
+    K25(25, u64),
+    K27(27, u64),
+    K29(29, u64),
+    K31(31, u64),
+    K41(41, u128),
+    K51(51, u128),
+    K63(63, u128),
+}
+
+pub fn check_k(k: usize) -> Result<usize> {
+    if SUPPORTED_K.contains(&k) {
+        Ok(k)
+    } else {
+        Err(unsupported_k(k))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set_of(k: usize, seqs: &[&str]) -> KmerSet {
+        let mut set = KmerSet::new(k).unwrap();
+        for seq in seqs {
+            set.insert_seq(seq.as_bytes());
+        }
+        set
+    }
+
+    #[test]
+    fn test_supported_k() {
+        assert!(KmerSet::new(20).is_err());
+        assert!(check_k(64).is_err());
+        for &k in SUPPORTED_K {
+            let set = KmerSet::new(k).unwrap();
+            assert_eq!(set.k(), k);
+            assert_eq!(set.int_type(), if k <= 32 { "u64" } else { "u128" });
+        }
+    }
+
+    #[test]
+    fn test_kmer_length() {
+        let seq = "ACGTACGTACGTACGTACGTACGTACGTACGTACGT";
+        for k in [15, 31] {
+            let set = set_of(k, &[seq]);
+            assert!(set.count() > 0);
+            assert!(set.kmers().all(|kmer| kmer.len() == k));
+            assert!(set.contains_seq(&seq.as_bytes()[..k])[0]);
+        }
+    }
+
+    #[test]
+    fn test_operations() {
+        let x = "AAAAAAAAAAAAAAA";
+        let y = "CCCCCCCCCCCCCCC";
+        let mut a = set_of(15, &[x, y]);
+        let mut b = set_of(15, &[x]);
+        assert_eq!((&mut a & &mut b).count(), 1);
+        let mut union = KmerSet::merge(15, vec![&mut a, &mut b]).unwrap();
+        assert_eq!(union.count(), 2);
+        union -= &mut b;
+        assert_eq!(
+            union.kmers().collect::<Vec<_>>(),
+            vec![y.as_bytes().to_vec()]
+        );
+        union |= &mut b;
+        union &= &mut a;
+        assert_eq!(union.count(), 2);
+        assert!(KmerSet::intersect(15, vec![]).unwrap().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot combine")]
+    fn test_mismatched_k() {
+        let mut a = KmerSet::new(15).unwrap();
+        a |= &mut KmerSet::new(21).unwrap();
+    }
+
+    #[test]
+    fn test_serialize() {
+        let set = set_of(31, &["ACGTACGTACGTACGTACGTACGTACGTACGTACGT"]);
+        let mut bytes = Vec::new();
+        set.serialize_into(&mut bytes).unwrap();
+        let read = KmerSet::deserialize_from(31, bytes.as_slice()).unwrap();
+        assert_eq!(
+            read.kmers().collect::<Vec<_>>(),
+            set.kmers().collect::<Vec<_>>()
+        );
+        assert!(KmerSet::deserialize_from(31, &bytes[..bytes.len() - 1]).is_err());
+    }
+}
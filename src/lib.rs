@@ -1,3 +1,24 @@
+#![allow(incomplete_features)]
 #![feature(generic_const_exprs)]
+#![allow(clippy::type_complexity)]
 
+pub mod batch;
+pub mod cache;
+pub mod catalog;
+pub mod error;
+pub mod expr;
+#[cfg(test)]
+mod fixtures;
+pub mod input;
+pub mod kmers;
+pub mod manifest;
+pub mod matrix;
+pub mod planner;
+pub mod query;
+pub mod reduce;
+pub mod search;
+pub mod server;
+pub mod similarity;
+pub mod unique;
+pub mod unitigs;
 pub mod utils;
@@ -2,37 +2,91 @@
 #![feature(generic_const_exprs)]
 #![allow(clippy::type_complexity)]
 
-mod utils;
-use bincode::{DefaultOptions, Options};
-use cbl::CBL;
+use anti_reindeer::batch::{factor, SharedResults};
+use anti_reindeer::catalog::{Provenance, SampleCatalog};
+use anti_reindeer::error::{GrimrError, Result};
+use anti_reindeer::expr::{parse_tag_expr, to_query};
+use anti_reindeer::input::InputFilter;
+use anti_reindeer::kmers::{check_k, KmerSet, DEFAULT_K};
+use anti_reindeer::manifest::{Building, Built, Manifest, MANIFEST_FILE};
+use anti_reindeer::matrix::PresenceMatrix;
+use anti_reindeer::planner::{explain, plan};
+use anti_reindeer::query::{Evaluator, Query};
+use anti_reindeer::reduce::Batching;
+use anti_reindeer::search::SequenceMatrix;
+use anti_reindeer::server::Server;
+use anti_reindeer::similarity::SimilarityMatrix;
+use anti_reindeer::unique::{file_names, sample_groups, tag_groups, unique_kmers, write_summary};
+use anti_reindeer::unitigs::write_unitigs;
+use anti_reindeer::utils::{parse_size, write_fasta, write_fasta_gz, write_kmers};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use serde::Deserialize;
 use serde_json::from_str;
-use std::collections::HashSet;
-use std::env;
+use std::collections::{BTreeSet, HashSet};
 use std::fs::{self, File};
-use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::io::{self, BufWriter, Write};
+use std::net::TcpListener;
 use std::path::Path;
-use std::path::PathBuf;
-use utils::{
-    cbl_printer, create_cbl_from_fasta, deserialize_cbl, read_fof_file_csv, serialize_cbl,
-};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-type T = u64;
-const K: usize = 21;
+// 0 with --quiet, 1 by default, one more per --verbose
+static VERBOSITY: AtomicUsize = AtomicUsize::new(1);
 
-// parse labels and obtain files for all, any, not all, not any
-fn parse_label_file<P: AsRef<Path>>(
-    path: P,
-) -> io::Result<(Vec<i32>, Vec<Vec<i32>>, Vec<Vec<i32>>, Vec<i32>)> {
-    let file = File::open(path)?;
-    let reader = io::BufReader::new(file);
+// progress messages go to stderr, so that results can be written to stdout
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if VERBOSITY.load(Ordering::Relaxed) >= 1 {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if VERBOSITY.load(Ordering::Relaxed) >= 2 {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+// a QUORUM line: k-mers present in at least min and at most max of the samples
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+struct QuorumGroup {
+    #[serde(default)]
+    min: usize,
+    max: Option<usize>,
+    samples: Vec<i32>,
+}
+
+// parse labels and obtain files for all, any, not all, not any, and the quorum groups
+// lines are numbered from first_line + 1 in errors, for queries taken from a part of a file
+fn parse_labels(
+    content: &str,
+    origin: &str,
+    first_line: usize,
+) -> Result<(
+    Vec<i32>,
+    Vec<Vec<i32>>,
+    Vec<Vec<i32>>,
+    Vec<i32>,
+    Vec<QuorumGroup>,
+)> {
+    let invalid = |number: usize, message: String| {
+        GrimrError::Parse(format!(
+            "{}:{}: {}",
+            origin,
+            first_line + number + 1,
+            message
+        ))
+    };
 
     let mut vec_all = Vec::new();
     let mut vec_any = Vec::new();
     let mut vec_not_all = Vec::new();
     let mut vec_not_any = Vec::new();
+    let mut vec_quorum = Vec::new();
 
-    for line in reader.lines() {
-        let line = line?;
+    for (number, line) in content.lines().enumerate() {
         let parts: Vec<&str> = line.split('\t').collect();
 
         if parts.len() != 3 {
@@ -44,8 +98,8 @@ fn parse_label_file<P: AsRef<Path>>(
 
         match typ {
             "ALL" | "NOT-ANY" => {
-                let vec: Vec<i32> = from_str(data_str)
-                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+                let vec: Vec<i32> =
+                    from_str(data_str).map_err(|e| invalid(number, e.to_string()))?;
                 if typ == "ALL" {
                     vec_all.extend(vec);
                 } else {
@@ -53,67 +107,153 @@ fn parse_label_file<P: AsRef<Path>>(
                 }
             }
             "ANY" | "NOT-ALL" => {
-                let vec_of_vec: Vec<Vec<i32>> = from_str(data_str)
-                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+                let vec_of_vec: Vec<Vec<i32>> =
+                    from_str(data_str).map_err(|e| invalid(number, e.to_string()))?;
                 if typ == "ANY" {
                     vec_any.extend(vec_of_vec);
                 } else {
                     vec_not_all.extend(vec_of_vec);
                 }
             }
-            _ => {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    "Unknown type encountered",
-                ))
+            "QUORUM" => {
+                let group: QuorumGroup =
+                    from_str(data_str).map_err(|e| invalid(number, e.to_string()))?;
+                vec_quorum.push(group);
             }
+            _ => return Err(invalid(number, format!("unknown type '{}'", typ))),
         }
     }
 
-    Ok((vec_all, vec_any, vec_not_all, vec_not_any))
+    Ok((vec_all, vec_any, vec_not_all, vec_not_any, vec_quorum))
 }
 
-// select files necessary to load in cbls and serialize
-fn select_files_to_load(
-    input_files: &[String],
+// the label sets as a query: ALL, ANY and QUORUM groups intersected, NOT-ALL and NOT-ANY
+// subtracted, without ALL the smallest ANY group comes first and is the starting universe
+fn label_sets_to_query(
     a_cup: &[i32],
     b_star: &[Vec<i32>],
     c_star: &[Vec<i32>],
     d_cup: &[i32],
-) -> io::Result<(Vec<String>, Vec<usize>)> {
-    let mut to_load = Vec::new();
-    let mut load_indices = Vec::new();
-    let mut indices = std::collections::HashSet::new();
-    // a_cup and b_star are empty, load everything
-    if a_cup.is_empty() && b_star.is_empty() {
-        for (i, file) in input_files.iter().enumerate() {
-            to_load.push(file.clone());
-            load_indices.push(i);
-        }
-    } else {
-        for &index in a_cup {
-            indices.insert(index);
+    quorums: &[QuorumGroup],
+) -> Query {
+    let ids = |vec: &[i32]| vec.iter().map(|&index| index as usize).collect::<Vec<_>>();
+    let mut b_star_work = b_star.to_vec();
+    let mut conjuncts: Vec<Query> = ids(a_cup).into_iter().map(Query::Leaf).collect();
+    if a_cup.is_empty() {
+        let (ind, smallest_vec_b) = find_smallest_vec_and_index(&b_star_work);
+        if !smallest_vec_b.is_empty() {
+            b_star_work.remove(ind);
+            conjuncts.push(Query::any_of(ids(&smallest_vec_b)));
         }
-        for vec in b_star {
-            for &index in vec {
-                indices.insert(index);
+    }
+    for c in c_star.iter().filter(|c| !c.is_empty()) {
+        conjuncts.push(Query::negate(Query::all_of(ids(c))));
+    }
+    for &index in d_cup {
+        conjuncts.push(Query::negate(Query::Leaf(index as usize)));
+    }
+    for b in b_star_work.iter().filter(|b| !b.is_empty()) {
+        conjuncts.push(Query::any_of(ids(b)));
+    }
+    for quorum in quorums {
+        conjuncts.push(Query::Quorum {
+            min: quorum.min,
+            max: quorum.max,
+            samples: ids(&quorum.samples),
+        });
+    }
+    Query::And(conjuncts)
+}
+
+// a query file holds either ALL/ANY/NOT-ALL/NOT-ANY/QUORUM lines or a tag expression
+// whose tags and sample names are resolved against the catalog
+fn parse_query_file(path: &str, catalog: &SampleCatalog) -> Result<Query> {
+    let content = fs::read_to_string(path).map_err(GrimrError::io(path))?;
+    parse_query_text(&content, path, 0, catalog)
+}
+
+fn parse_query_text(
+    content: &str,
+    origin: &str,
+    first_line: usize,
+    catalog: &SampleCatalog,
+) -> Result<Query> {
+    if content.lines().any(|line| line.split('\t').count() == 3) {
+        let (a_cup, b_star, c_star, d_cup, quorums) = parse_labels(content, origin, first_line)?;
+        return Ok(label_sets_to_query(
+            &a_cup, &b_star, &c_star, &d_cup, &quorums,
+        ));
+    }
+    let expr = parse_tag_expr(content)?;
+    to_query(&expr, catalog)
+}
+
+// a batch file holds named queries, each a `>name` line followed by the content of a query
+// file, label lines or a tag expression
+fn parse_batch_file(path: &str, catalog: &SampleCatalog) -> Result<Vec<(String, Query)>> {
+    let content = fs::read_to_string(path).map_err(GrimrError::io(path))?;
+    let invalid = |number: usize, message: String| {
+        GrimrError::Parse(format!("{}:{}: {}", path, number + 1, message))
+    };
+    // name, number of the header line and text of every query
+    let mut sections: Vec<(String, usize, String)> = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        if let Some(name) = line.strip_prefix('>') {
+            let name = name.trim();
+            if name.is_empty() || name.contains(|c: char| c == '/' || c.is_whitespace()) {
+                return Err(invalid(number, format!("invalid query name '{}'", name)));
             }
-        }
-        for vec in c_star {
-            for &index in vec {
-                indices.insert(index);
+            if sections.iter().any(|(other, _, _)| other == name) {
+                return Err(invalid(
+                    number,
+                    format!("query '{}' is defined twice", name),
+                ));
             }
+            sections.push((name.to_string(), number, String::new()));
+        } else if let Some((_, _, text)) = sections.last_mut() {
+            text.push_str(line);
+            text.push('\n');
+        } else if !line.trim().is_empty() && !line.trim_start().starts_with('#') {
+            return Err(invalid(number, "expected a '>name' line".to_string()));
         }
-        for &index in d_cup {
-            indices.insert(index);
-        }
+    }
+    if sections.is_empty() {
+        return Err(GrimrError::Parse(format!("{}: no query", path)));
+    }
+    sections
+        .into_iter()
+        .map(|(name, number, text)| {
+            let query =
+                parse_query_text(&text, path, number + 1, catalog).map_err(|e| match e {
+                    GrimrError::InvalidQuery(message) => {
+                        GrimrError::InvalidQuery(format!("query '{}': {}", name, message))
+                    }
+                    e => e,
+                })?;
+            Ok((name, query))
+        })
+        .collect()
+}
 
-        // Load files by iterating over input_files to maintain order
-        for (i, file) in input_files.iter().enumerate() {
-            if indices.contains(&(i as i32)) {
-                to_load.push(file.clone());
-                load_indices.push(i);
-            }
+// select files necessary to load in cbls and serialize
+fn select_files_to_load(
+    input_files: &[String],
+    query: &Query,
+) -> Result<(Vec<String>, Vec<usize>)> {
+    let mut to_load = Vec::new();
+    let mut load_indices = Vec::new();
+    // the universe is needed, load everything
+    let indices = if query.needs_universe() {
+        (0..input_files.len()).collect()
+    } else {
+        query.samples()
+    };
+
+    // Load files by iterating over input_files to maintain order
+    for (i, file) in input_files.iter().enumerate() {
+        if indices.contains(&i) {
+            to_load.push(file.clone());
+            load_indices.push(i);
         }
     }
     Ok((to_load, load_indices))
@@ -169,273 +309,1095 @@ fn create_unique_vec(
 }
 
 fn create_and_serialize_cbls(
-    input_files: Vec<String>,
+    catalog: &SampleCatalog,
+    metadata_path: &str,
     output_dir: &str,
-    a_cup: Vec<i32>,
-    b_star: Vec<Vec<i32>>,
-    c_star: Vec<Vec<i32>>,
-    d_cup: Vec<i32>,
-) {
+    query: &Query,
+    k: usize,
+    filter: &InputFilter,
+    building: Building,
+) -> Result<Manifest> {
+    // create cbls only if needed (all if the universe is needed, else, only indexes that appear)
+    let input_files = catalog.paths();
+    let (_, indices) = select_files_to_load(&input_files, query)?;
+    index_samples(
+        catalog,
+        metadata_path,
+        output_dir,
+        &indices,
+        k,
+        filter,
+        building,
+    )
+}
+
+// serialize the cbls of the given samples and describe them in a new manifest
+fn index_samples(
+    catalog: &SampleCatalog,
+    metadata_path: &str,
+    output_dir: &str,
+    indices: &[usize],
+    k: usize,
+    filter: &InputFilter,
+    building: Building,
+) -> Result<Manifest> {
     // dir where serialized cbls are stored
     let _ = fs::remove_file(output_dir);
-    fs::create_dir_all(output_dir).unwrap();
-    // create cbls only if needed (all if a, b empty, else, only indexes that appear)
-    let to_load_values =
-        select_files_to_load(&input_files, &a_cup, &b_star, &c_star, &d_cup).unwrap();
-    let (to_load, indices) = to_load_values;
+    fs::create_dir_all(output_dir).map_err(GrimrError::io(output_dir))?;
+    let mut manifest = Manifest::new(k, metadata_path, catalog)?;
+    manifest.filter = *filter;
+    let built = manifest.build_cbls(output_dir, indices, building, report);
+    // k, samples, k-mer counts and hashes of the cbls, for later queries
+    // saved even after an error, so that indexing again resumes from the cbls already built
+    manifest.save(output_dir)?;
+    built?;
+    Ok(manifest)
+}
 
-    for (i, input_filename) in to_load.iter().enumerate() {
-        let cbl = create_cbl_from_fasta(input_filename);
-        // serialize the cbl and save it to a file
-        let output_filename = format!("{}/{}.cbl", output_dir, indices[i]);
-        serialize_cbl(&cbl, &output_filename);
+// one line per serialized cbl
+fn report(built: &Built) {
+    info!(
+        "[{}/{}] sample {} ({}): {} k-mers in {:.1?}",
+        built.done,
+        built.total,
+        built.sample.id,
+        built.sample.name,
+        built.entry.kmers,
+        built.elapsed
+    );
+}
+
+// samples whose cbls the query reads, every sample of the index if it needs the universe
+fn needed_samples(manifest: &Manifest, query: &Query) -> Vec<usize> {
+    let mut samples = query.samples();
+    // saved results are not part of the universe but may be named by the query
+    if query.needs_universe() {
+        samples.extend(manifest.catalog.sources().map(|s| s.id));
     }
+    samples.into_iter().collect()
+}
 
-    // write the cbl names to be loaded
-    let to_load_index = format!("{}/to_load.txt", output_dir);
-    let mut file = File::create(to_load_index).unwrap();
-    for index in &indices {
-        writeln!(file, "{}", index).unwrap();
+// `index`: build the cbls used by the query, or every cbl without one
+// an existing index keeps its samples and k and only gets the cbls it is missing
+fn index_metadata(
+    metadata_path: &str,
+    output_dir: &str,
+    query_file: Option<&str>,
+    k: Option<usize>,
+    filter: Option<InputFilter>,
+    building: Building,
+) -> Result<Manifest> {
+    if !Path::new(output_dir).join(MANIFEST_FILE).exists() {
+        let catalog = SampleCatalog::from_metadata(metadata_path)?;
+        let query = match query_file {
+            Some(path) => parse_query_file(path, &catalog)?,
+            None => Query::And(vec![]),
+        };
+        let k = k.unwrap_or(DEFAULT_K);
+        let manifest = create_and_serialize_cbls(
+            &catalog,
+            metadata_path,
+            output_dir,
+            &query,
+            k,
+            &filter.unwrap_or_default(),
+            building,
+        )?;
+        info!("Built {} CBLs in '{}'", manifest.cbls.len(), output_dir);
+        return Ok(manifest);
     }
+    let mut manifest = Manifest::load(output_dir)?;
+    manifest.check_k(k)?;
+    manifest.check_filter(filter.as_ref())?;
+    let needed = match query_file {
+        Some(path) => needed_samples(&manifest, &parse_query_file(path, &manifest.catalog)?),
+        None => manifest.catalog.sources().map(|s| s.id).collect(),
+    };
+    let built = manifest.build_missing(output_dir, &needed, building, report);
+    manifest.save(output_dir)?;
+    info!("Built {} missing CBLs in '{}'", built?.len(), output_dir);
+    Ok(manifest)
 }
 
-fn query_cbls(
-    a_cup: Vec<i32>,
-    b_star: Vec<Vec<i32>>,
-    c_star: Vec<Vec<i32>>,
-    d_cup: Vec<i32>,
+// a sample given by id or name on the command line
+fn sample_id(manifest: &Manifest, index_dir: &str, name: &str) -> Result<usize> {
+    manifest.find_sample(name).map(|s| s.id).ok_or_else(|| {
+        GrimrError::MissingSample(format!("no sample '{}' in index '{}'", name, index_dir))
+    })
+}
+
+// `index add|remove|rebuild`: update single samples of an index
+fn update_index(action: &str, output_dir: &str, args: &ArgMatches) -> Result<()> {
+    let mut manifest = Manifest::load(output_dir)?;
+    match action {
+        "add" => {
+            let path = args.value_of("sequence_file").unwrap();
+            let tags = args
+                .values_of("tags")
+                .map_or(vec![], |tags| tags.map(|tag| tag.to_string()).collect());
+            let id = manifest.add_sample(output_dir, path, tags)?;
+            let kmers = manifest.build_cbl(output_dir, id)?.kmers;
+            info!("Added sample {} ({} k-mers) from {}", id, kmers, path);
+        }
+        "remove" => {
+            let id = sample_id(&manifest, output_dir, args.value_of("sample").unwrap())?;
+            let sample = manifest.remove_sample(output_dir, id)?;
+            info!("Removed sample {} ({})", sample.id, sample.name);
+        }
+        "rebuild" => {
+            let id = sample_id(&manifest, output_dir, args.value_of("sample").unwrap())?;
+            let kmers = manifest.build_cbl(output_dir, id)?.kmers;
+            info!("Rebuilt sample {} ({} k-mers)", id, kmers);
+        }
+        _ => unreachable!("unknown index subcommand"),
+    }
+    manifest.save(output_dir)
+}
+
+fn query_cbls(query: &Query, output_dir: &str, batching: Batching) -> Result<KmerSet> {
+    let mut manifest = Manifest::load(output_dir)?;
+    let needed = needed_samples(&manifest, query);
+    // the index caches cbls by sample, the ones this query needs are built on first use
+    let built =
+        manifest.build_missing(output_dir, &needed, Building::new(batching.threads), report)?;
+    if !built.is_empty() {
+        info!("Built {} missing CBLs: {:?}", built.len(), built);
+        manifest.save(output_dir)?;
+    }
+    manifest.verify(output_dir, &needed)?;
+    // every serialized cbl of a sequence file, in case the universe must be loaded
+    let cbl_files_to_load = manifest.source_ids();
+    // order the operations by the k-mer counts of the cbls
+    let cardinalities = manifest.cardinalities();
+    let planned = plan(query, &cardinalities);
+    debug!("{}", explain(&planned, &cardinalities, &manifest.catalog));
+    let evaluator = Evaluator::new(output_dir, manifest.k, &cbl_files_to_load, batching)?;
+    evaluator.eval(&planned)
+}
+
+// `query --save`: register the result in the index as a sample named after the query
+fn save_result(
+    index_dir: &str,
+    name: &str,
+    query_file: &str,
+    text: String,
+    query: &Query,
+    cbl: &KmerSet,
+) -> Result<usize> {
+    // reloaded, the query may have built missing cbls
+    let mut manifest = Manifest::load(index_dir)?;
+    let provenance = Provenance {
+        query: text,
+        samples: needed_samples(&manifest, query),
+    };
+    let id = manifest.add_derived(index_dir, name, query_file, provenance, cbl)?;
+    manifest.save(index_dir)?;
+    info!("Saved the result as sample {} ({})", id, name);
+    Ok(id)
+}
+
+// build the cbls of the given samples that are missing and check the others
+fn prepare_samples(
+    manifest: &mut Manifest,
+    index_dir: &str,
+    ids: &[usize],
+    threads: usize,
+) -> Result<()> {
+    let built = manifest.build_missing(index_dir, ids, Building::new(threads), report)?;
+    if !built.is_empty() {
+        info!("Built {} missing CBLs: {:?}", built.len(), built);
+        manifest.save(index_dir)?;
+    }
+    manifest.verify(index_dir, ids)
+}
+
+// every sample of the index, ready to be read, and the results saved with `query --save`
+// only if with_derived: the other commands compare the samples of the cohort
+fn load_all_samples(
+    index_dir: &str,
+    threads: usize,
+    with_derived: bool,
+) -> Result<(Manifest, Vec<usize>)> {
+    let mut manifest = Manifest::load(index_dir)?;
+    let ids: Vec<usize> = match with_derived {
+        true => manifest.catalog.samples.iter().map(|s| s.id).collect(),
+        false => manifest.catalog.sources().map(|s| s.id).collect(),
+    };
+    prepare_samples(&mut manifest, index_dir, &ids, threads)?;
+    Ok((manifest, ids))
+}
+
+// `query-seq`: the fraction of the k-mers of every sequence found in every sample of the index
+fn query_sequences(fasta_path: &str, index_dir: &str, threads: usize) -> Result<SequenceMatrix> {
+    let (manifest, ids) = load_all_samples(index_dir, threads, false)?;
+    SequenceMatrix::build(fasta_path, index_dir, manifest.k, &ids, &manifest.catalog)
+}
+
+// `similarity`: the k-mers shared by every pair of samples of the index
+fn sample_similarity(
+    index_dir: &str,
+    block_size: usize,
+    threads: usize,
+) -> Result<SimilarityMatrix> {
+    let (manifest, ids) = load_all_samples(index_dir, threads, false)?;
+    SimilarityMatrix::build(index_dir, manifest.k, &ids, &manifest.catalog, block_size)
+}
+
+// `unique`: the k-mers of every sample, or of every tag, found in no other sample of the index
+// written to one file per group in output_dir, with a summary table
+fn extract_unique(
+    index_dir: &str,
     output_dir: &str,
-    batch_size: usize,
-) -> io::Result<CBL<K, T>> {
-    // load cbls and build union for A cup and D cup
-    let mut global_cbl;
-    let mut b_star_work = b_star.clone();
+    by_tag: bool,
+    format: &str,
+    threads: usize,
+) -> Result<()> {
+    let mut manifest = Manifest::load(index_dir)?;
+    // saved query results are made of the k-mers of other samples, none would be unique
+    let ids: Vec<usize> = manifest.catalog.sources().map(|s| s.id).collect();
+    prepare_samples(&mut manifest, index_dir, &ids, threads)?;
+    let groups = match by_tag {
+        true => tag_groups(&ids, &manifest.catalog),
+        false => sample_groups(&ids, &manifest.catalog),
+    };
+    let unique = unique_kmers(index_dir, manifest.k, &ids, &groups)?;
+    fs::create_dir_all(output_dir).map_err(GrimrError::io(output_dir))?;
+    let extension = file_extension(format);
+    let mut outputs = Vec::with_capacity(groups.len());
+    // names may hold paths and collide once made into file names, summary.tsv keeps them
+    for ((group, kmers), name) in groups.iter().zip(&unique).zip(file_names(&groups)) {
+        let path = Path::new(output_dir).join(format!("{}.{}", name, extension));
+        let path = path.to_str().unwrap();
+        write_kmer_set(kmers, format, open_output(Some(path))?, path)?;
+        debug!(
+            "{} unique k-mers of {} written to {}",
+            kmers.count(),
+            group.name,
+            path
+        );
+        outputs.push(path.to_string());
+    }
+    let summary = Path::new(output_dir).join("summary.tsv");
+    let summary = summary.to_str().unwrap();
+    write_summary(open_output(Some(summary))?, &groups, &unique, &outputs)
+        .map_err(GrimrError::io(summary))?;
+    info!(
+        "Unique k-mers of {} {} written to '{}', counts in {}",
+        groups.len(),
+        if by_tag { "tags" } else { "samples" },
+        output_dir,
+        summary
+    );
+    Ok(())
+}
 
-    // get all serialized cbl names in case the universe must be loaded
-    let file_path = format!("{}/to_load.txt", output_dir);
-    let file_toload_cbl = File::open(file_path)?;
-    let reader = BufReader::new(file_toload_cbl);
-    let mut cbl_files_to_load = Vec::new();
+// `batch`: run the named queries of a batch file, the subqueries they share computed once
+// the result of every query is written to its own file in output_dir, with a summary table
+fn query_batch(
+    batch_file: &str,
+    index_dir: &str,
+    output_dir: &str,
+    format: &str,
+    batching: Batching,
+) -> Result<()> {
+    let mut manifest = Manifest::load(index_dir)?;
+    let queries = parse_batch_file(batch_file, &manifest.catalog)?;
+    let needed: BTreeSet<usize> = queries
+        .iter()
+        .flat_map(|(_, query)| needed_samples(&manifest, query))
+        .collect();
+    let needed: Vec<usize> = needed.into_iter().collect();
+    prepare_samples(&mut manifest, index_dir, &needed, batching.threads)?;
+    let cardinalities = manifest.cardinalities();
+    let planned: Vec<Query> = queries
+        .iter()
+        .map(|(_, query)| plan(query, &cardinalities))
+        .collect();
+    let factored = factor(&planned);
+    let shared = SharedResults::new(&factored);
+    info!(
+        "Running {} queries, {} shared subqueries computed once",
+        queries.len(),
+        shared.len()
+    );
+    let universe = manifest.source_ids();
+    let evaluator =
+        Evaluator::new(index_dir, manifest.k, &universe, batching)?.with_shared(&shared);
+    fs::create_dir_all(output_dir).map_err(GrimrError::io(output_dir))?;
+    let mut summary = vec!["query\tkmers\toutput".to_string()];
+    for ((name, _), query) in queries.iter().zip(&factored) {
+        debug!(
+            "{}:\n{}",
+            name,
+            explain(query, &cardinalities, &manifest.catalog)
+        );
+        let cbl = evaluator.eval(query)?;
+        let path = Path::new(output_dir).join(format!("{}.{}", name, file_extension(format)));
+        let path = path.to_str().unwrap();
+        write_kmer_set(&cbl, format, open_output(Some(path))?, path)?;
+        debug!("{} k-mers of {} written to {}", cbl.count(), name, path);
+        summary.push(format!("{}\t{}\t{}", name, cbl.count(), path));
+    }
+    let summary_path = Path::new(output_dir).join("summary.tsv");
+    let summary_path = summary_path.to_str().unwrap();
+    fs::write(summary_path, summary.join("\n") + "\n").map_err(GrimrError::io(summary_path))?;
+    info!(
+        "Results of {} queries written to '{}', counts in {}",
+        queries.len(),
+        output_dir,
+        summary_path
+    );
+    Ok(())
+}
 
-    for line in reader.lines() {
-        let index = line?;
-        cbl_files_to_load.push(index);
+// `serve`: answer queries over HTTP until the process is stopped, every cbl built beforehand
+fn serve_index(index_dir: &str, address: &str, cache_size: u64, batching: Batching) -> Result<()> {
+    // queries may name saved results
+    let (manifest, _) = load_all_samples(index_dir, batching.threads, true)?;
+    let listener = TcpListener::bind(address).map_err(GrimrError::io(address))?;
+    let local = listener.local_addr()?;
+    info!(
+        "Serving index '{}' on http://{} with a {} byte cache",
+        index_dir, local, cache_size
+    );
+    Server::new(index_dir, manifest, cache_size, batching).serve(listener);
+    Ok(())
+}
+
+// the file given with --output, stdout if there is none or it is `-`
+fn open_output(path: Option<&str>) -> Result<Box<dyn Write>> {
+    match path {
+        None | Some("-") => Ok(Box::new(BufWriter::new(io::stdout()))),
+        Some(path) => {
+            let file = File::create(path).map_err(GrimrError::io(path))?;
+            Ok(Box::new(BufWriter::new(file)))
+        }
     }
+}
 
-    if a_cup.is_empty() {
-        let input_filenames: Vec<_> = if b_star.is_empty() {
-            cbl_files_to_load
-                .iter()
-                .map(|index| format!("{}/{}.cbl", output_dir, index))
-                .collect()
-        } else {
-            let (ind, smallest_vec_b) = find_smallest_vec_and_index(&b_star_work);
-            b_star_work.remove(ind); // remove smallest b from b*
-            smallest_vec_b
-                .iter()
-                .map(|index| format!("{}/{}.cbl", output_dir, index))
-                .collect()
-        };
-        if batch_size > 1 {
-            let mut input_iter = input_filenames.chunks(batch_size);
-            global_cbl = if let Some(input_filename_chunk) = input_iter.next() {
-                let mut cbls_chunk: Vec<_> = input_filename_chunk
-                    .iter()
-                    .map(|input_filename| deserialize_cbl(input_filename))
-                    .collect();
-                CBL::<K, T>::merge(cbls_chunk.iter_mut().collect())
-            } else {
-                unreachable!()
-            };
-            for input_filename_chunk in input_iter {
-                let mut cbls_chunk: Vec<_> = input_filename_chunk
-                    .iter()
-                    .map(|input_filename| deserialize_cbl(input_filename))
-                    .collect();
-                global_cbl |= &mut CBL::<K, T>::merge(cbls_chunk.iter_mut().collect());
+// extension of the files written in a format of write_kmer_set
+fn file_extension(format: &str) -> &'static str {
+    match format {
+        "fasta" => "fa",
+        "fasta-gz" => "fa.gz",
+        "kmers" => "txt",
+        "unitigs" => "unitigs.fa",
+        _ => "cbl",
+    }
+}
+
+// write the k-mers of a set in a format that needs nothing else than the set
+fn write_kmer_set<W: Write>(cbl: &KmerSet, format: &str, mut writer: W, path: &str) -> Result<()> {
+    let with_path = GrimrError::io(path);
+    match format {
+        // every format writes a valid output when there is no k-mer
+        "fasta" => write_fasta(cbl, &mut writer, path)?,
+        "kmers" => write_kmers(cbl, &mut writer, path)?,
+        "unitigs" => write_unitigs(cbl, &mut writer).map_err(with_path)?,
+        "fasta-gz" => write_fasta_gz(cbl, &mut writer, path)?,
+        "cbl" => match cbl.serialize_into(&mut writer) {
+            // the set does not know where it is written
+            Err(GrimrError::Io { source, .. }) => return Err(with_path(source)),
+            result => {
+                result?;
+                writer.flush().map_err(with_path)?;
             }
-        } else {
-            let mut input_iter = input_filenames.iter();
-            global_cbl = if let Some(input_filename) = input_iter.next() {
-                deserialize_cbl(input_filename)
+        },
+        format => unreachable!("unknown format {}", format),
+    }
+    Ok(())
+}
+
+// write a result in the format given with --format
+fn write_result(
+    cbl: &KmerSet,
+    query: &Query,
+    index_dir: &str,
+    catalog: &SampleCatalog,
+    args: &ArgMatches,
+) -> Result<()> {
+    let output_path = args.value_of("output");
+    let mut writer = open_output(output_path)?;
+    let path = output_path.unwrap_or("<stdout>");
+    match args.value_of("format").unwrap() {
+        format @ ("matrix" | "matrix-bin") => {
+            // which of the samples touched by the query contain each result k-mer
+            let ids: Vec<usize> = query.samples().into_iter().collect();
+            let matrix = PresenceMatrix::build(cbl, index_dir, &ids, catalog)?;
+            if format == "matrix" {
+                matrix
+                    .write_tsv_to(&mut writer)
+                    .map_err(GrimrError::io(path))?;
             } else {
-                unreachable!()
-            };
-            for input_filename in input_iter {
-                global_cbl |= &mut deserialize_cbl(input_filename);
+                matrix.write_binary_to(&mut writer)?;
             }
         }
+        format => write_kmer_set(cbl, format, writer, path)?,
+    }
+    let format = args.value_of("format").unwrap();
+    let destination = match output_path {
+        None | Some("-") => "stdout",
+        Some(path) => path,
+    };
+    info!(
+        "{} k-mers written to {} as {}",
+        cbl.count(),
+        destination,
+        format
+    );
+    Ok(())
+}
+
+// one line per property of the index, then one line per sample
+fn write_stats<W: Write>(manifest: &Manifest, mut writer: W) -> io::Result<()> {
+    let cardinalities = manifest.cardinalities();
+    writeln!(writer, "format version\t{}", manifest.format_version)?;
+    writeln!(writer, "k\t{}", manifest.k)?;
+    writeln!(writer, "integer type\t{}", manifest.int_type)?;
+    writeln!(writer, "filter\t{}", manifest.filter)?;
+    writeln!(writer, "samples\t{}", manifest.catalog.len())?;
+    writeln!(writer, "built CBLs\t{}", manifest.cbls.len())?;
+    let total: usize = manifest.cbls.iter().map(|c| c.kmers).sum();
+    writeln!(writer, "k-mers\t{}", total)?;
+    writeln!(writer, "\nid\tname\tkmers\tpath\ttags")?;
+    for sample in &manifest.catalog.samples {
+        let kmers = cardinalities
+            .get(sample.id)
+            .map_or("-".to_string(), |kmers| kmers.to_string());
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}",
+            sample.id,
+            sample.name,
+            kmers,
+            sample.entry(),
+            sample.tags.join(",")
+        )?;
+    }
+    writer.flush()
+}
+
+// the filtering options of `index`, none if neither is given
+fn input_filter(args: &ArgMatches) -> Option<InputFilter> {
+    let min_quality = args.value_of("min_quality").map(|q| q.parse().unwrap());
+    let min_abundance = args.value_of("min_abundance").map(|n| n.parse().unwrap());
+    if min_quality.is_none() && min_abundance.is_none() {
+        return None;
+    }
+    Some(InputFilter {
+        min_quality,
+        min_abundance: min_abundance.unwrap_or(1),
+    })
+}
+
+fn phred_score(value: String) -> std::result::Result<(), String> {
+    match value.parse::<u8>() {
+        Ok(q) if q <= 93 => Ok(()),
+        _ => Err(format!("'{}' is not a Phred score between 0 and 93", value)),
+    }
+}
+
+fn positive(value: String) -> std::result::Result<(), String> {
+    match value.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err(format!("'{}' is not a positive integer", value)),
+    }
+}
+
+fn memory_size(value: String) -> std::result::Result<(), String> {
+    parse_size(&value).map(|_| ()).map_err(|e| e.to_string())
+}
+
+fn fraction(value: String) -> std::result::Result<(), String> {
+    match value.parse::<f64>() {
+        Ok(f) if (0.0..=1.0).contains(&f) => Ok(()),
+        _ => Err(format!("'{}' is not a fraction between 0 and 1", value)),
+    }
+}
+
+fn supported_k(value: String) -> std::result::Result<(), String> {
+    match value.parse::<usize>() {
+        Ok(k) => check_k(k).map(|_| ()).map_err(|e| e.to_string()),
+        Err(_) => Err(format!("invalid k '{}'", value)),
+    }
+}
+
+fn cli() -> App<'static, 'static> {
+    let index_dir = Arg::with_name("index_dir")
+        .short("d")
+        .long("index-dir")
+        .value_name("DIR")
+        .default_value("serialized_cbls")
+        .help("Directory of the serialized CBLs and of the manifest");
+    let output = Arg::with_name("output")
+        .short("o")
+        .long("output")
+        .value_name("FILE")
+        .help("Write the result to FILE instead of stdout ('-' for stdout)");
+    let sample = Arg::with_name("sample")
+        .required(true)
+        .help("Sample id or name");
+    App::new("anti_reindeer")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Set operations over the k-mers of many samples, stored as CBLs")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .multiple(true)
+                .global(true)
+                .help("Print more progress messages on stderr, such as the query plan"),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .short("q")
+                .long("quiet")
+                .global(true)
+                .conflicts_with("verbose")
+                .help("Only print errors on stderr"),
+        )
+        .subcommand(
+            SubCommand::with_name("index")
+                .about("Build the CBLs of the samples of a metadata file")
+                .setting(AppSettings::SubcommandsNegateReqs)
+                .arg(
+                    Arg::with_name("metadata")
+                        .required(true)
+                        .help("Metadata file: a sequence file path and its tags on every line"),
+                )
+                .arg(index_dir.clone())
+                .arg(
+                    Arg::with_name("query")
+                        .long("query")
+                        .value_name("FILE")
+                        .help("Only build the CBLs this query file uses"),
+                )
+                .arg(
+                    Arg::with_name("k")
+                        .long("k")
+                        .value_name("K")
+                        .validator(supported_k)
+                        .help("k-mer length of a new index [default: 21]"),
+                )
+                .arg(
+                    Arg::with_name("threads")
+                        .short("t")
+                        .long("threads")
+                        .value_name("N")
+                        .default_value("1")
+                        .validator(positive)
+                        .help("Number of samples indexed at a time, each holding one CBL in memory, or N with --min-abundance N"),
+                )
+                .arg(
+                    Arg::with_name("max_in_flight")
+                        .long("max-in-flight")
+                        .value_name("N")
+                        .validator(positive)
+                        .help("Most CBLs held in memory by all the threads at a time, a sample filtered with --min-abundance N holding N [default: no limit]"),
+                )
+                .arg(
+                    Arg::with_name("min_quality")
+                        .long("min-quality")
+                        .value_name("Q")
+                        .validator(phred_score)
+                        .help("Skip the k-mers of FASTQ reads covering a base of Phred quality below Q"),
+                )
+                .arg(
+                    Arg::with_name("min_abundance")
+                        .long("min-abundance")
+                        .value_name("N")
+                        .validator(positive)
+                        .help("Skip the k-mers seen fewer than N times in a sample [default: 1]"),
+                )
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("Index a new sequence file, it gets the next free id")
+                        .arg(index_dir.clone())
+                        .arg(
+                            Arg::with_name("sequence_file")
+                                .required(true)
+                                .help("Sequence file, or k-mer dump prefixed with 'kmers:'"),
+                        )
+                        .arg(Arg::with_name("tags").multiple(true)),
+                )
+                .subcommand(
+                    SubCommand::with_name("remove")
+                        .about("Forget a sample and delete its CBL")
+                        .arg(index_dir.clone())
+                        .arg(sample.clone()),
+                )
+                .subcommand(
+                    SubCommand::with_name("rebuild")
+                        .about("Build the CBL of a sample again from its sequence file")
+                        .arg(index_dir.clone())
+                        .arg(sample.clone()),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("query")
+                .about("Run a query file and write the k-mers of the result")
+                .arg(
+                    Arg::with_name("query_file")
+                        .required(true)
+                        .help("Label file or tag expression"),
+                )
+                .arg(index_dir.clone())
+                .arg(output.clone())
+                .arg(
+                    Arg::with_name("format")
+                        .short("f")
+                        .long("format")
+                        .possible_values(&[
+                            "fasta", "fasta-gz", "kmers", "unitigs", "cbl", "matrix", "matrix-bin",
+                        ])
+                        .default_value("fasta")
+                        .help("One FASTA record per k-mer (fasta, or gzip-compressed fasta-gz), one k-mer per line (kmers), unitigs, a binary CBL (cbl), or a sample presence matrix (TSV or bincode)"),
+                )
+                .arg(
+                    Arg::with_name("batch_size")
+                        .short("b")
+                        .long("batch-size")
+                        .value_name("N")
+                        .default_value("4")
+                        .validator(positive)
+                        .help("Number of CBLs deserialized and combined at a time"),
+                )
+                .arg(
+                    Arg::with_name("threads")
+                        .short("t")
+                        .long("threads")
+                        .value_name("N")
+                        .default_value("1")
+                        .validator(positive)
+                        .help("Number of batches deserialized and combined at a time"),
+                )
+                .arg(
+                    Arg::with_name("max_memory")
+                        .long("max-memory")
+                        .value_name("SIZE")
+                        .validator(memory_size)
+                        .help("Shrink batches so the loaded CBLs fit in SIZE bytes (K, M, G suffixes)"),
+                )
+                .arg(
+                    Arg::with_name("save")
+                        .long("save")
+                        .value_name("NAME")
+                        .help("Also save the result in the index as a sample later queries can use"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("batch")
+                .about("Run the named queries of a batch file, computing the subqueries they share once")
+                .arg(
+                    Arg::with_name("batch_file")
+                        .required(true)
+                        .help("Queries, each a '>name' line followed by label lines or a tag expression"),
+                )
+                .arg(index_dir.clone())
+                .arg(
+                    Arg::with_name("output_dir")
+                        .short("o")
+                        .long("output-dir")
+                        .value_name("DIR")
+                        .required(true)
+                        .help("Directory of the result of every query and of summary.tsv"),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .short("f")
+                        .long("format")
+                        .possible_values(&["fasta", "fasta-gz", "kmers", "unitigs", "cbl"])
+                        .default_value("fasta"),
+                )
+                .arg(
+                    Arg::with_name("batch_size")
+                        .short("b")
+                        .long("batch-size")
+                        .value_name("N")
+                        .default_value("4")
+                        .validator(positive)
+                        .help("Number of CBLs deserialized and combined at a time"),
+                )
+                .arg(
+                    Arg::with_name("threads")
+                        .short("t")
+                        .long("threads")
+                        .value_name("N")
+                        .default_value("1")
+                        .validator(positive)
+                        .help("Number of batches deserialized and combined at a time"),
+                )
+                .arg(
+                    Arg::with_name("max_memory")
+                        .long("max-memory")
+                        .value_name("SIZE")
+                        .validator(memory_size)
+                        .help("Shrink batches so the loaded CBLs fit in SIZE bytes (K, M, G suffixes)"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("query-seq")
+                .about("Write the fraction of the k-mers of every sequence of a FASTA file found in every sample")
+                .arg(
+                    Arg::with_name("fasta")
+                        .required(true)
+                        .help("FASTA or FASTQ file of the sequences to look up, possibly compressed"),
+                )
+                .arg(index_dir.clone())
+                .arg(output.clone())
+                .arg(
+                    Arg::with_name("min_fraction")
+                        .long("min-fraction")
+                        .value_name("F")
+                        .default_value("0")
+                        .validator(fraction)
+                        .help("Write 0 for the samples containing less than this fraction of the k-mers of a sequence"),
+                )
+                .arg(
+                    Arg::with_name("threads")
+                        .short("t")
+                        .long("threads")
+                        .value_name("N")
+                        .default_value("1")
+                        .validator(positive)
+                        .help("Number of missing CBLs built at a time"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("similarity")
+                .about("Write the Jaccard index and containments of every pair of samples")
+                .arg(index_dir.clone())
+                .arg(output.clone())
+                .arg(
+                    Arg::with_name("format")
+                        .short("f")
+                        .long("format")
+                        .possible_values(&["pairs", "jaccard", "containment", "intersection"])
+                        .default_value("pairs")
+                        .help("One line per pair with every measure, or a square matrix of one of them"),
+                )
+                .arg(
+                    Arg::with_name("batch_size")
+                        .short("b")
+                        .long("batch-size")
+                        .value_name("N")
+                        .default_value("4")
+                        .validator(positive)
+                        .help("Number of CBLs kept in memory, every other CBL is read once per N samples"),
+                )
+                .arg(
+                    Arg::with_name("threads")
+                        .short("t")
+                        .long("threads")
+                        .value_name("N")
+                        .default_value("1")
+                        .validator(positive)
+                        .help("Number of missing CBLs built at a time"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("unique")
+                .about("Write the k-mers found in a single sample, or a single tag, for every one of them")
+                .arg(index_dir.clone())
+                .arg(
+                    Arg::with_name("output_dir")
+                        .short("o")
+                        .long("output-dir")
+                        .value_name("DIR")
+                        .required(true)
+                        .help("Directory of the output of every sample or tag and of summary.tsv"),
+                )
+                .arg(
+                    Arg::with_name("by_tag")
+                        .long("by-tag")
+                        .help("The k-mers of the samples of every tag found in no sample without it"),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .short("f")
+                        .long("format")
+                        .possible_values(&["fasta", "fasta-gz", "kmers", "unitigs", "cbl"])
+                        .default_value("fasta"),
+                )
+                .arg(
+                    Arg::with_name("threads")
+                        .short("t")
+                        .long("threads")
+                        .value_name("N")
+                        .default_value("1")
+                        .validator(positive)
+                        .help("Number of missing CBLs built at a time"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("Answer queries over a local HTTP/JSON API, keeping the CBLs read in memory")
+                .arg(index_dir.clone())
+                .arg(
+                    Arg::with_name("address")
+                        .short("a")
+                        .long("address")
+                        .value_name("HOST:PORT")
+                        .default_value("127.0.0.1:8080")
+                        .help("Address to listen on, port 0 picks a free port"),
+                )
+                .arg(
+                    Arg::with_name("cache_size")
+                        .long("cache-size")
+                        .value_name("SIZE")
+                        .default_value("1G")
+                        .validator(memory_size)
+                        .help("Bytes of CBL files kept in memory, with the copies queries make of them, the least recently used are dropped beyond it (K, M, G suffixes)"),
+                )
+                .arg(
+                    Arg::with_name("batch_size")
+                        .short("b")
+                        .long("batch-size")
+                        .value_name("N")
+                        .default_value("4")
+                        .validator(positive)
+                        .help("Number of CBLs combined at a time"),
+                )
+                .arg(
+                    Arg::with_name("threads")
+                        .short("t")
+                        .long("threads")
+                        .value_name("N")
+                        .default_value("1")
+                        .validator(positive)
+                        .help("Number of queries answered at a time, of batches combined at a time by a query, and of missing CBLs built at a time"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("explain")
+                .about("Print the plan of a query file without running it")
+                .arg(Arg::with_name("query_file").required(true))
+                .arg(index_dir.clone())
+                .arg(
+                    Arg::with_name("metadata")
+                        .short("m")
+                        .long("metadata")
+                        .value_name("FILE")
+                        .help("Metadata file to resolve tags with when there is no index yet"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("Describe an index and the k-mer counts of its samples")
+                .arg(index_dir.clone())
+                .arg(output.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("dump")
+                .about("Write the k-mers of one sample")
+                .arg(sample)
+                .arg(index_dir)
+                .arg(output)
+                .arg(
+                    Arg::with_name("format")
+                        .short("f")
+                        .long("format")
+                        .possible_values(&["fasta", "fasta-gz", "kmers", "unitigs", "cbl"])
+                        .default_value("fasta"),
+                ),
+        )
+}
+
+fn run(matches: &ArgMatches) -> Result<()> {
+    let verbosity = if matches.is_present("quiet") {
+        0
     } else {
-        let input_filenames: Vec<_> = a_cup
-            .iter()
-            .map(|index| format!("{}/{}.cbl", output_dir, index))
-            .collect();
-        if batch_size > 1 {
-            let mut input_iter = input_filenames.chunks(batch_size);
-            global_cbl = if let Some(input_filename_chunk) = input_iter.next() {
-                let mut cbls_chunk: Vec<_> = input_filename_chunk
-                    .iter()
-                    .map(|input_filename| deserialize_cbl(input_filename))
-                    .collect();
-                CBL::<K, T>::intersect(cbls_chunk.iter_mut().collect())
-            } else {
-                unreachable!()
-            };
-            for input_filename_chunk in input_iter {
-                let mut cbls_chunk: Vec<_> = input_filename_chunk
-                    .iter()
-                    .map(|input_filename| deserialize_cbl(input_filename))
-                    .collect();
-                global_cbl &= &mut CBL::<K, T>::intersect(cbls_chunk.iter_mut().collect());
+        1 + matches.occurrences_of("verbose") as usize
+    };
+    VERBOSITY.store(verbosity, Ordering::Relaxed);
+    match matches.subcommand() {
+        ("index", Some(args)) => match args.subcommand() {
+            ("", None) => {
+                let k = args.value_of("k").map(|k| k.parse().unwrap());
+                let building = Building::new(args.value_of("threads").unwrap().parse().unwrap())
+                    .with_max_in_flight(args.value_of("max_in_flight").map(|n| n.parse().unwrap()));
+                index_metadata(
+                    args.value_of("metadata").unwrap(),
+                    args.value_of("index_dir").unwrap(),
+                    args.value_of("query"),
+                    k,
+                    input_filter(args),
+                    building,
+                )?;
             }
-        } else {
-            let mut input_iter = input_filenames.iter();
-            global_cbl = if let Some(input_filename) = input_iter.next() {
-                deserialize_cbl(input_filename)
-            } else {
-                unreachable!()
+            (action, Some(sub_args)) => {
+                // -d may come before the action, which then only has the default
+                let given = match sub_args.occurrences_of("index_dir") {
+                    0 => args,
+                    _ => sub_args,
+                };
+                update_index(action, given.value_of("index_dir").unwrap(), sub_args)?
+            }
+            (_, None) => unreachable!("index subcommands have arguments"),
+        },
+        ("query", Some(args)) => {
+            let index_dir = args.value_of("index_dir").unwrap();
+            let manifest = Manifest::load(index_dir)?;
+            let query_file = args.value_of("query_file").unwrap();
+            // the query is read before the output is written, it may replace the query file
+            let save = match args.value_of("save") {
+                Some(name) => {
+                    manifest.check_new_name(index_dir, name)?;
+                    let text =
+                        fs::read_to_string(query_file).map_err(GrimrError::io(query_file))?;
+                    Some((name, text))
+                }
+                None => None,
             };
-            for input_filename in input_iter {
-                global_cbl &= &mut deserialize_cbl(input_filename);
+            let catalog = manifest.catalog;
+            let query = parse_query_file(query_file, &catalog)?;
+            let batch_size = args.value_of("batch_size").unwrap().parse().unwrap();
+            let threads = args.value_of("threads").unwrap().parse().unwrap();
+            let max_memory = args
+                .value_of("max_memory")
+                .map(|size| parse_size(size).unwrap());
+            let batching = Batching::new(batch_size, threads).with_max_memory(max_memory);
+            let cbl = query_cbls(&query, index_dir, batching)?;
+            write_result(&cbl, &query, index_dir, &catalog, args)?;
+            if let Some((name, text)) = save {
+                save_result(index_dir, name, query_file, text, &query, &cbl)?;
             }
         }
-    }
-
-    for c in &c_star {
-        //NOT ALL
-        if !c.is_empty() {
-            let input_filenames: Vec<_> = c
-                .iter()
-                .map(|index| format!("{}/{}.cbl", output_dir, index))
-                .collect();
-            if batch_size > 1 {
-                let mut input_iter = input_filenames.chunks(batch_size);
-                let mut local_cbl = if let Some(input_filename_chunk) = input_iter.next() {
-                    let mut cbls_chunk: Vec<_> = input_filename_chunk
-                        .iter()
-                        .map(|input_filename| deserialize_cbl(input_filename))
-                        .collect();
-                    CBL::<K, T>::intersect(cbls_chunk.iter_mut().collect())
-                } else {
-                    unreachable!()
-                };
-                for input_filename_chunk in input_iter {
-                    let mut cbls_chunk: Vec<_> = input_filename_chunk
-                        .iter()
-                        .map(|input_filename| deserialize_cbl(input_filename))
-                        .collect();
-                    local_cbl &= &mut CBL::<K, T>::intersect(cbls_chunk.iter_mut().collect());
-                }
-                global_cbl -= &mut local_cbl;
-            } else {
-                let mut input_iter = input_filenames.iter();
-                let mut local_cbl = if let Some(input_filename) = input_iter.next() {
-                    deserialize_cbl(input_filename)
-                } else {
-                    unreachable!()
-                };
-                for input_filename in input_iter {
-                    local_cbl &= &mut deserialize_cbl(input_filename);
+        ("batch", Some(args)) => {
+            let batch_size = args.value_of("batch_size").unwrap().parse().unwrap();
+            let threads = args.value_of("threads").unwrap().parse().unwrap();
+            let max_memory = args
+                .value_of("max_memory")
+                .map(|size| parse_size(size).unwrap());
+            query_batch(
+                args.value_of("batch_file").unwrap(),
+                args.value_of("index_dir").unwrap(),
+                args.value_of("output_dir").unwrap(),
+                args.value_of("format").unwrap(),
+                Batching::new(batch_size, threads).with_max_memory(max_memory),
+            )?;
+        }
+        ("explain", Some(args)) => {
+            // the index gives the catalog and the k-mer counts, a metadata file only the catalog
+            let manifest = Manifest::load(args.value_of("index_dir").unwrap());
+            let (catalog, cardinalities) = match (manifest, args.value_of("metadata")) {
+                (_, Some(metadata)) => {
+                    (SampleCatalog::from_metadata(metadata)?, Default::default())
                 }
-                global_cbl -= &mut local_cbl;
-            }
+                (Ok(manifest), None) => (manifest.catalog.clone(), manifest.cardinalities()),
+                (Err(e), None) => return Err(e),
+            };
+            let query = parse_query_file(args.value_of("query_file").unwrap(), &catalog)?;
+            let planned = plan(&query, &cardinalities);
+            print!("{}", explain(&planned, &cardinalities, &catalog));
         }
-    }
-    for index in &d_cup {
-        //NOT ANY
-        let input_filename = format!("{}/{}.cbl", output_dir, index);
-        global_cbl -= &mut deserialize_cbl(&input_filename);
-    }
-    for b in b_star_work {
-        if !b.is_empty() {
-            let input_filenames: Vec<_> = b
-                .iter()
-                .map(|index| format!("{}/{}.cbl", output_dir, index))
-                .collect();
-            if batch_size > 1 {
-                let mut input_iter = input_filenames.chunks(batch_size);
-                let mut local_cbl = if let Some(input_filename_chunk) = input_iter.next() {
-                    let mut cbls_chunk: Vec<_> = input_filename_chunk
-                        .iter()
-                        .map(|input_filename| {
-                            &mut global_cbl & &mut deserialize_cbl(input_filename)
-                        })
-                        .collect();
-                    CBL::<K, T>::merge(cbls_chunk.iter_mut().collect())
-                } else {
-                    unreachable!()
-                };
-                for input_filename_chunk in input_iter {
-                    let mut cbls_chunk: Vec<_> = input_filename_chunk
-                        .iter()
-                        .map(|input_filename| {
-                            &mut global_cbl & &mut deserialize_cbl(input_filename)
-                        })
-                        .collect();
-                    local_cbl |= &mut CBL::<K, T>::merge(cbls_chunk.iter_mut().collect());
+        ("query-seq", Some(args)) => {
+            let threads = args.value_of("threads").unwrap().parse().unwrap();
+            let matrix = query_sequences(
+                args.value_of("fasta").unwrap(),
+                args.value_of("index_dir").unwrap(),
+                threads,
+            )?;
+            let min_fraction = args.value_of("min_fraction").unwrap().parse().unwrap();
+            let output_path = args.value_of("output");
+            matrix
+                .write_tsv_to(open_output(output_path)?, min_fraction)
+                .map_err(GrimrError::io(output_path.unwrap_or("<stdout>")))?;
+            let found = (0..matrix.len())
+                .filter(|&i| !matrix.hits(i, min_fraction).is_empty())
+                .count();
+            info!(
+                "{} of {} sequences found in some sample",
+                found,
+                matrix.len()
+            );
+        }
+        ("similarity", Some(args)) => {
+            let batch_size = args.value_of("batch_size").unwrap().parse().unwrap();
+            let threads = args.value_of("threads").unwrap().parse().unwrap();
+            let matrix =
+                sample_similarity(args.value_of("index_dir").unwrap(), batch_size, threads)?;
+            let output_path = args.value_of("output");
+            let writer = open_output(output_path)?;
+            match args.value_of("format").unwrap() {
+                "pairs" => matrix.write_pairs_to(writer),
+                "jaccard" => {
+                    matrix.write_matrix_to(writer, |m, i, j| format!("{:.6}", m.jaccard(i, j)))
                 }
-                global_cbl = local_cbl;
-            } else {
-                let mut input_iter = input_filenames.iter();
-                let mut local_cbl = if let Some(input_filename) = input_iter.next() {
-                    &mut global_cbl & &mut deserialize_cbl(input_filename)
-                } else {
-                    unreachable!()
-                };
-                for input_filename in input_iter {
-                    local_cbl |= &mut (&mut global_cbl & &mut deserialize_cbl(input_filename));
+                "containment" => {
+                    matrix.write_matrix_to(writer, |m, i, j| format!("{:.6}", m.containment(i, j)))
                 }
-                global_cbl = local_cbl;
+                _ => matrix.write_matrix_to(writer, SimilarityMatrix::intersection),
             }
+            .map_err(GrimrError::io(output_path.unwrap_or("<stdout>")))?;
+            info!(
+                "{} pairs of samples compared",
+                matrix.len() * matrix.len().saturating_sub(1) / 2
+            );
+        }
+        ("unique", Some(args)) => {
+            let threads = args.value_of("threads").unwrap().parse().unwrap();
+            extract_unique(
+                args.value_of("index_dir").unwrap(),
+                args.value_of("output_dir").unwrap(),
+                args.is_present("by_tag"),
+                args.value_of("format").unwrap(),
+                threads,
+            )?;
+        }
+        ("serve", Some(args)) => {
+            let batch_size = args.value_of("batch_size").unwrap().parse().unwrap();
+            let threads = args.value_of("threads").unwrap().parse().unwrap();
+            let cache_size = parse_size(args.value_of("cache_size").unwrap())?;
+            serve_index(
+                args.value_of("index_dir").unwrap(),
+                args.value_of("address").unwrap(),
+                cache_size,
+                Batching::new(batch_size, threads),
+            )?;
         }
+        ("stats", Some(args)) => {
+            let manifest = Manifest::load(args.value_of("index_dir").unwrap())?;
+            let output_path = args.value_of("output");
+            write_stats(&manifest, open_output(output_path)?)
+                .map_err(GrimrError::io(output_path.unwrap_or("<stdout>")))?;
+        }
+        ("dump", Some(args)) => {
+            let index_dir = args.value_of("index_dir").unwrap();
+            let manifest = Manifest::load(index_dir)?;
+            let name = args.value_of("sample").unwrap();
+            let query = Query::Leaf(sample_id(&manifest, index_dir, name)?);
+            let cbl = query_cbls(&query, index_dir, Batching::new(1, 1))?;
+            write_result(&cbl, &query, index_dir, &manifest.catalog, args)?;
+        }
+        _ => unreachable!("subcommand required"),
     }
-    Ok(global_cbl)
+    Ok(())
 }
 
 fn main() {
-    // parse args
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 4 || args.len() > 5 {
-        eprintln!(
-            "Usage: {} <mode> <input_metadata> <label_file> [<output_dir>]",
-            args[0]
-        );
-        std::process::exit(1);
-    }
-    let mode = args[1].clone();
-    let input_file_list = args[2].clone();
-    let label_file_list = args[3].clone();
-    let output_dir = if args.len() == 5 {
-        args[4].clone()
-    } else {
-        "serialized_cbls".to_string()
-    };
-    let labels = parse_label_file(label_file_list).unwrap(); //todo test
-    let (a_cup, b_star, c_star, d_cup) = labels;
-    if mode == "index" {
-        // read the fof
-        let (input_files, _col_nb) = read_fof_file_csv(&input_file_list).unwrap(); // use of col_nb?
-                                                                                   // create and serialize CBLs
-        if !Path::new(&output_dir).exists() {
-            create_and_serialize_cbls(input_files, &output_dir, a_cup, b_star, c_star, d_cup);
-        } else {
-            println!(
-                "Output directory '{}' already exists, skipping creation of CBLs.",
-                output_dir
-            );
+    let matches = cli().get_matches();
+    match run(&matches) {
+        // the reader of stdout stopped early, as `head` does
+        Err(GrimrError::Io { source, .. }) if source.kind() == io::ErrorKind::BrokenPipe => {}
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(e.exit_code());
         }
-    } else if mode == "query" {
-        let cbl = query_cbls(a_cup, b_star, c_star, d_cup, &output_dir, 4).unwrap();
-        let output_path = PathBuf::from(&output_dir).join("output_anti_reindeer_query.txt");
-        let _ = fs::remove_file(&output_path);
-        cbl_printer(&cbl, output_path.to_str().unwrap()).expect("Failed to print CBL");
-        println!("Query results written to: {}", output_path.display());
+        Ok(()) => {}
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use anti_reindeer::utils::{
+        cbl_printer, create_cbl_from_fasta, deserialize_cbl, read_fof_file_csv,
+    };
+    use clap::ErrorKind;
     use needletail::parse_fastx_file;
+    use std::io::{BufRead, BufReader, Read};
 
     use super::*;
 
@@ -538,7 +1500,7 @@ mod tests {
     }
     #[test]
     fn test_printer() {
-        let cbl_a = deserialize_cbl("test_files/0.cbl");
+        let cbl_a = deserialize_cbl("test_files/0.cbl", DEFAULT_K).unwrap();
         let output_path = "test_files/test_printer.fa";
         let _ = fs::remove_file(output_path);
         cbl_printer(&cbl_a, output_path).unwrap();
@@ -585,8 +1547,8 @@ mod tests {
         let c_star: Vec<Vec<i32>> = vec![vec![1]];
         let d_cup: Vec<i32> = vec![1];
 
-        let loaded_files_values =
-            select_files_to_load(&input_files, &a_cup, &b_star, &c_star, &d_cup).unwrap();
+        let query = label_sets_to_query(&a_cup, &b_star, &c_star, &d_cup, &[]);
+        let loaded_files_values = select_files_to_load(&input_files, &query).unwrap();
         let (loaded_files, _) = loaded_files_values;
         assert_eq!(loaded_files, input_files);
     }
@@ -603,8 +1565,8 @@ mod tests {
         let c_star: Vec<Vec<i32>> = vec![vec![1]];
         let d_cup: Vec<i32> = vec![1];
 
-        let loaded_files_values =
-            select_files_to_load(&input_files, &a_cup, &b_star, &c_star, &d_cup).unwrap();
+        let query = label_sets_to_query(&a_cup, &b_star, &c_star, &d_cup, &[]);
+        let loaded_files_values = select_files_to_load(&input_files, &query).unwrap();
         let (loaded_files, _) = loaded_files_values;
         assert_eq!(
             loaded_files,
@@ -621,26 +1583,19 @@ mod tests {
     ) {
         let _ = fs::remove_file(test_output_dir);
         fs::create_dir_all(test_output_dir).expect("Failed to create test output directory");
-        let labels = parse_label_file(query_path).unwrap();
-        let (a_cup, b_star, c_star, d_cup) = labels;
-        let (input_files, _col_nb) = read_fof_file_csv(metadata_path).unwrap();
+        let catalog = SampleCatalog::from_metadata(metadata_path).unwrap();
+        let query = parse_query_file(query_path, &catalog).unwrap();
         create_and_serialize_cbls(
-            input_files,
-            test_output_dir,
-            a_cup.clone(),
-            b_star.clone(),
-            c_star.clone(),
-            d_cup.clone(),
-        );
-        let cbl_act = query_cbls(
-            a_cup.clone(),
-            b_star.clone(),
-            c_star.clone(),
-            d_cup.clone(),
+            &catalog,
+            metadata_path,
             test_output_dir,
-            4,
+            &query,
+            DEFAULT_K,
+            &InputFilter::default(),
+            Building::new(1),
         )
         .unwrap();
+        let cbl_act = query_cbls(&query, test_output_dir, Batching::new(4, 1)).unwrap();
         // assert!(!cbl_act.is_empty());
 
         let mut expected_content = parse_fastx_file(expected_output_path).unwrap();
@@ -671,19 +1626,23 @@ mod tests {
         let query_path = format!("{}/query1.txt", dir);
         let o_dir = format!("{}/output_tser/", dir);
         let p_path = format!("{}/output_tser/output.fa", dir);
-        let labels = parse_label_file(query_path).unwrap();
-        let (a_cup, b_star, c_star, d_cup) = labels;
-        let (input_files, _col_nb) = read_fof_file_csv(&metadata_path).unwrap();
+        let content = fs::read_to_string(&query_path).unwrap();
+        let labels = parse_labels(&content, &query_path, 0).unwrap();
+        let (a_cup, b_star, c_star, d_cup, quorums) = labels;
+        let catalog = SampleCatalog::from_metadata(&metadata_path).unwrap();
+        let query = label_sets_to_query(&a_cup, &b_star, &c_star, &d_cup, &quorums);
         create_and_serialize_cbls(
-            input_files,
+            &catalog,
+            &metadata_path,
             &o_dir,
-            a_cup.clone(),
-            b_star.clone(),
-            c_star.clone(),
-            d_cup.clone(),
-        );
+            &query,
+            DEFAULT_K,
+            &InputFilter::default(),
+            Building::new(1),
+        )
+        .unwrap();
         let input_filename = format!("{}/0.cbl", &o_dir);
-        let cbl = deserialize_cbl(&input_filename);
+        let cbl = deserialize_cbl(&input_filename, DEFAULT_K).unwrap();
         cbl_printer(&cbl, &p_path).expect("Failed to print CBL");
         assert_eq!(cbl.count(), 2);
     }
@@ -1028,4 +1987,843 @@ mod tests {
             &actual_output_path,
         );
     }
+    #[test]
+    fn test_full_index_and_query_flow15_expr() {
+        let test_input_dir = "correctness_test";
+        let test_output_dir = "correctness_test/output15_expr";
+        let metadata_path = format!("{}/fof_tags.txt", test_input_dir);
+        let query_path = format!("{}/query15_expr.txt", test_input_dir);
+        let expected_output_path = format!("{}/expected_15.fa", test_input_dir);
+        let actual_output_path = format!("{}/output_query15expr_results.fa", test_output_dir);
+        func_test(
+            test_output_dir,
+            &query_path,
+            &metadata_path,
+            &expected_output_path,
+            &actual_output_path,
+        );
+    }
+    #[test]
+    fn test_full_index_and_query_flow16() {
+        let test_input_dir = "correctness_test";
+        let test_output_dir = "correctness_test/output16";
+        let metadata_path = format!("{}/fof.txt", test_input_dir);
+        let query_path = format!("{}/query16.txt", test_input_dir);
+        let expected_output_path = format!("{}/expected_16.fa", test_input_dir);
+        let actual_output_path = format!("{}/output_query16_results.fa", test_output_dir);
+        func_test(
+            test_output_dir,
+            &query_path,
+            &metadata_path,
+            &expected_output_path,
+            &actual_output_path,
+        );
+    }
+    #[test]
+    fn test_full_index_and_query_flow16_expr() {
+        let test_input_dir = "correctness_test";
+        let test_output_dir = "correctness_test/output16_expr";
+        let metadata_path = format!("{}/fof_tags.txt", test_input_dir);
+        let query_path = format!("{}/query16_expr.txt", test_input_dir);
+        let expected_output_path = format!("{}/expected_16.fa", test_input_dir);
+        let actual_output_path = format!("{}/output_query16expr_results.fa", test_output_dir);
+        func_test(
+            test_output_dir,
+            &query_path,
+            &metadata_path,
+            &expected_output_path,
+            &actual_output_path,
+        );
+    }
+    #[test]
+    fn test_index_and_query_with_k() {
+        let test_output_dir = "correctness_test/output_k15";
+        let _ = fs::remove_dir_all(test_output_dir);
+        let catalog = SampleCatalog::from_metadata("correctness_test/fof.txt").unwrap();
+        let query = parse_query_file("correctness_test/query1.txt", &catalog).unwrap();
+        create_and_serialize_cbls(
+            &catalog,
+            "correctness_test/fof.txt",
+            test_output_dir,
+            &query,
+            15,
+            &InputFilter::default(),
+            Building::new(4).with_max_in_flight(Some(2)),
+        )
+        .unwrap();
+        let manifest = Manifest::load(test_output_dir).unwrap();
+        assert_eq!(manifest.k, 15);
+        assert!(manifest.check_k(Some(DEFAULT_K)).is_err());
+        let cbl = query_cbls(&query, test_output_dir, Batching::new(4, 1)).unwrap();
+        assert_eq!(cbl.k(), 15);
+        assert!(!cbl.is_empty());
+        assert!(cbl.kmers().all(|kmer| kmer.len() == 15));
+        let _ = fs::remove_dir_all(test_output_dir);
+    }
+
+    // run the command line, clap errors are invalid arguments
+    fn run_args(list: &[&str]) -> Result<()> {
+        let args = std::iter::once("anti_reindeer").chain(list.iter().copied());
+        let matches = cli()
+            .get_matches_from_safe(args)
+            .map_err(|e| GrimrError::InvalidArgument(e.message))?;
+        run(&matches)
+    }
+
+    #[test]
+    fn test_cli() {
+        let matches = cli()
+            .get_matches_from_safe(vec![
+                "bin", "-vv", "query", "q.txt", "-b", "2", "-f", "kmers",
+            ])
+            .unwrap();
+        assert_eq!(matches.occurrences_of("verbose"), 2);
+        let (name, args) = matches.subcommand();
+        let args = args.unwrap();
+        assert_eq!(name, "query");
+        assert_eq!(args.value_of("batch_size"), Some("2"));
+        assert_eq!(args.value_of("index_dir"), Some("serialized_cbls"));
+        assert_eq!(args.value_of("output"), None);
+        assert_eq!(args.value_of("threads"), Some("1"));
+        assert_eq!(args.value_of("max_memory"), None);
+        let error = |list: Vec<&str>| cli().get_matches_from_safe(list).unwrap_err().kind;
+        assert_eq!(
+            error(vec!["bin", "index", "fof.txt", "--k", "20"]),
+            ErrorKind::ValueValidation
+        );
+        assert_eq!(
+            error(vec!["bin", "query", "q.txt", "-b", "0"]),
+            ErrorKind::ValueValidation
+        );
+        assert_eq!(
+            error(vec!["bin", "query", "q.txt", "--max-memory", "2X"]),
+            ErrorKind::ValueValidation
+        );
+        assert_eq!(
+            error(vec!["bin", "serve", "--cache-size", "lots"]),
+            ErrorKind::ValueValidation
+        );
+        let matches = cli().get_matches_from_safe(vec!["bin", "serve"]).unwrap();
+        let args = matches.subcommand_matches("serve").unwrap();
+        assert_eq!(args.value_of("address"), Some("127.0.0.1:8080"));
+        assert_eq!(args.value_of("cache_size"), Some("1G"));
+        assert_eq!(
+            error(vec!["bin", "query", "q.txt", "-f", "bam"]),
+            ErrorKind::InvalidValue
+        );
+        assert_eq!(
+            error(vec!["bin", "query"]),
+            ErrorKind::MissingRequiredArgument
+        );
+        assert_eq!(
+            error(vec!["bin", "stats", "--help"]),
+            ErrorKind::HelpDisplayed
+        );
+        let matches = cli()
+            .get_matches_from_safe(vec!["bin", "index", "remove", "F1", "-d", "dir"])
+            .unwrap();
+        let (name, args) = matches.subcommand_matches("index").unwrap().subcommand();
+        assert_eq!(name, "remove");
+        assert_eq!(args.unwrap().value_of("index_dir"), Some("dir"));
+    }
+
+    #[test]
+    fn test_run_errors() {
+        let dir = "test_files/output_run_errors";
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let run_with = |list: &[&str]| run_args(list).unwrap_err().exit_code();
+        let labels = format!("{}/labels.txt", dir);
+        fs::write(&labels, "1\tALL\t[0, 1\n").unwrap();
+        let metadata = format!("{}/metadata.csv", dir);
+        fs::write(&metadata, "test_files/none.fa A\n").unwrap();
+        let index = format!("{}/index", dir);
+        assert_eq!(
+            run_with(&["index", &metadata, "--query", &labels, "-d", &index]),
+            3
+        );
+        fs::write(&labels, "1\tALL\t[0]\n").unwrap();
+        assert_eq!(
+            run_with(&["index", &metadata, "--query", &labels, "-d", &index]),
+            2
+        );
+        assert_eq!(run_with(&["query", &labels, "-d", dir]), 4);
+        assert_eq!(run_with(&["explain", &labels, "-d", dir]), 4);
+        assert_eq!(run_with(&["query", "-d", dir]), 1);
+        assert_eq!(run_with(&["index", &metadata, "--k", "20"]), 1);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_index_with_filters() {
+        let dir = "test_files/output_index_filters";
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let metadata = format!("{}/metadata.csv", dir);
+        fs::write(
+            &metadata,
+            "test_files/reads.fq.gz reads\ntest_files/reads.fq.bz2 reads\n",
+        )
+        .unwrap();
+        let index = format!("{}/index", dir);
+        let filters = ["--min-quality", "20", "--min-abundance", "2"];
+        let mut args = vec!["index", metadata.as_str(), "-d", index.as_str()];
+        args.extend(filters);
+        run_args(&args).unwrap();
+        let manifest = Manifest::load(&index).unwrap();
+        assert_eq!(manifest.filter.min_quality, Some(20));
+        assert_eq!(
+            manifest.cbls.iter().map(|c| c.kmers).collect::<Vec<_>>(),
+            vec![10, 10]
+        );
+        // the same filters, or none, reuse the index, others are refused
+        run_args(&args).unwrap();
+        run_args(&["index", &metadata, "-d", &index]).unwrap();
+        let refused = run_args(&["index", &metadata, "-d", &index, "--min-abundance", "3"]);
+        assert_eq!(refused.unwrap_err().exit_code(), 4);
+        let stats = format!("{}/stats.txt", dir);
+        run_args(&["stats", "-d", &index, "-o", &stats]).unwrap();
+        assert!(fs::read_to_string(&stats)
+            .unwrap()
+            .contains("filter\tminimum base quality 20, minimum k-mer abundance 2\n"));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_index_kmer_dumps() {
+        let dir = "test_files/output_index_dumps";
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let metadata = format!("{}/metadata.csv", dir);
+        fs::write(
+            &metadata,
+            "test_files/reads.fq A\nkmers:test_files/reads_counts.tsv A\n",
+        )
+        .unwrap();
+        let index = format!("{}/index", dir);
+        run_args(&["index", &metadata, "-d", &index, "--min-abundance", "2"]).unwrap();
+        run_args(&[
+            "index",
+            "add",
+            "kmers:test_files/reads_kmers.txt",
+            "B",
+            "-d",
+            &index,
+        ])
+        .unwrap();
+        let manifest = Manifest::load(&index).unwrap();
+        assert_eq!(manifest.catalog.samples[1].name, "reads_counts");
+        assert_eq!(manifest.catalog.get(2).unwrap().name, "reads_kmers");
+        // the reads, their counts and their k-mers seen twice give the same cbl
+        let hashes: HashSet<&str> = manifest.cbls.iter().map(|c| c.hash.as_str()).collect();
+        assert_eq!((manifest.cbls.len(), hashes.len()), (3, 1));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_output_formats() {
+        let dir = "correctness_test/output_formats";
+        let _ = fs::remove_dir_all(dir);
+        let index = format!("{}/index", dir);
+        run_args(&["index", "correctness_test/fof.txt", "-d", &index]).unwrap();
+        let query_file = format!("{}/query.txt", dir);
+        let output = |name: &str| format!("{}/{}", dir, name);
+        for (expression, found) in [("F1 OR F2", true), ("F1 AND NOT F1", false)] {
+            fs::write(&query_file, expression).unwrap();
+            let cbl = query_cbls(
+                &parse_query_file(&query_file, &Manifest::load(&index).unwrap().catalog).unwrap(),
+                &index,
+                Batching::new(4, 1),
+            )
+            .unwrap();
+            assert_eq!(cbl.is_empty(), !found);
+            for format in ["fasta", "fasta-gz", "kmers", "unitigs", "cbl"] {
+                let path = output(format);
+                let _ = fs::remove_file(&path);
+                run_args(&[
+                    "query",
+                    &query_file,
+                    "-d",
+                    &index,
+                    "-f",
+                    format,
+                    "-o",
+                    &path,
+                ])
+                .unwrap();
+                // an empty result still gives a file, valid for its format
+                assert!(Path::new(&path).exists());
+            }
+            let fasta = fs::read_to_string(output("fasta")).unwrap();
+            assert_eq!(fasta.lines().count(), 2 * cbl.count());
+            let gz = File::open(output("fasta-gz")).unwrap();
+            let mut decoded = String::new();
+            flate2::read::GzDecoder::new(gz)
+                .read_to_string(&mut decoded)
+                .unwrap();
+            assert_eq!(decoded, fasta);
+            let binary = deserialize_cbl(&output("cbl"), DEFAULT_K).unwrap();
+            assert_eq!(
+                binary.kmers().collect::<Vec<_>>(),
+                cbl.kmers().collect::<Vec<_>>()
+            );
+        }
+        let empty = KmerSet::new(DEFAULT_K).unwrap();
+        cbl_printer(&empty, &output("printer.fa")).unwrap();
+        assert_eq!(fs::read(output("printer.fa")).unwrap().len(), 0);
+        // write errors name the output whatever the format
+        let mut cbl = KmerSet::new(DEFAULT_K).unwrap();
+        cbl.insert_seq(b"GTTGTCTATGCCAGGGCGACGACATTGCGG");
+        for format in ["fasta", "fasta-gz", "kmers", "unitigs", "cbl"] {
+            let full: &mut [u8] = &mut [];
+            match write_kmer_set(&cbl, format, full, "full.out") {
+                Err(GrimrError::Io { path, .. }) => assert_eq!(path, Some("full.out".into())),
+                other => panic!("unexpected {:?} for {}", other, format),
+            }
+        }
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_query_command() {
+        let dir = "correctness_test/output_query_command";
+        let _ = fs::remove_dir_all(dir);
+        let query_file = "correctness_test/query1.txt";
+        run_args(&[
+            "index",
+            "correctness_test/fof.txt",
+            "--query",
+            query_file,
+            "-d",
+            dir,
+        ])
+        .unwrap();
+        let output = format!("{}/result.txt", dir);
+        run_args(&[
+            "query", query_file, "-d", dir, "-f", "kmers", "-o", &output, "-b", "1",
+        ])
+        .unwrap();
+        let catalog = Manifest::load(dir).unwrap().catalog;
+        let query = parse_query_file(query_file, &catalog).unwrap();
+        let expected: HashSet<Vec<u8>> = query_cbls(&query, dir, Batching::new(4, 1))
+            .unwrap()
+            .kmers()
+            .collect();
+        let content = fs::read_to_string(&output).unwrap();
+        let kmers: HashSet<Vec<u8>> = content
+            .lines()
+            .map(|line| line.as_bytes().to_vec())
+            .collect();
+        assert_eq!(kmers, expected);
+
+        let matrix = format!("{}/matrix.tsv", dir);
+        run_args(&[
+            "query", query_file, "-d", dir, "-f", "matrix", "-o", &matrix,
+        ])
+        .unwrap();
+        let content = fs::read_to_string(&matrix).unwrap();
+        assert_eq!(content.lines().count(), expected.len() + 1);
+
+        let unitigs = format!("{}/unitigs.fa", dir);
+        run_args(&[
+            "query", query_file, "-d", dir, "-f", "unitigs", "-o", &unitigs,
+        ])
+        .unwrap();
+        let content = fs::read_to_string(&unitigs).unwrap();
+        let kmers: usize = content
+            .lines()
+            .filter_map(|line| line.split("kmers=").nth(1))
+            .map(|rest| rest.split(' ').next().unwrap().parse::<usize>().unwrap())
+            .sum();
+        assert_eq!(kmers, expected.len());
+
+        let stats = format!("{}/stats.tsv", dir);
+        run_args(&["stats", "-d", dir, "-o", &stats]).unwrap();
+        let content = fs::read_to_string(&stats).unwrap();
+        assert!(content.starts_with("format version\t1\nk\t21\n"));
+        assert!(content.contains("\n2\tF3\t-\t"));
+
+        // dumping a sample builds its cbl
+        let dump = format!("{}/F3.fa", dir);
+        run_args(&["dump", "F3", "-d", dir, "-o", &dump]).unwrap();
+        let cbl = create_cbl_from_fasta("correctness_test/F3.fa", DEFAULT_K).unwrap();
+        let records = fs::read_to_string(&dump).unwrap();
+        assert_eq!(
+            records.lines().filter(|line| line.starts_with('>')).count(),
+            cbl.count()
+        );
+        assert!(Manifest::load(dir).unwrap().has_cbl(2));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_saved_results() {
+        let dir = "correctness_test/output_saved_results";
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        run_args(&["index", "correctness_test/fof.txt", "-d", dir]).unwrap();
+        let write_query = |name: &str, content: &str| {
+            let path = format!("{}/{}.txt", dir, name);
+            fs::write(&path, content).unwrap();
+            path
+        };
+        let kmers = |query_file: &str| {
+            let output = format!("{}/result.txt", dir);
+            run_args(&["query", query_file, "-d", dir, "-f", "kmers", "-o", &output]).unwrap();
+            let content = fs::read_to_string(&output).unwrap();
+            content
+                .lines()
+                .map(|line| line.to_string())
+                .collect::<HashSet<_>>()
+        };
+        let shared = write_query("shared", "F1 AND F2");
+        let complement = write_query("complement", "NOT F3");
+        let before = kmers(&complement);
+        let output = format!("{}/shared.fa", dir);
+        run_args(&[
+            "query", &shared, "-d", dir, "-f", "kmers", "-o", &output, "--save", "shared",
+        ])
+        .unwrap();
+        let manifest = Manifest::load(dir).unwrap();
+        let sample = manifest.find_sample("shared").unwrap();
+        assert_eq!(sample.id, 8);
+        assert_eq!(
+            sample.provenance,
+            Some(Provenance {
+                query: "F1 AND F2".to_string(),
+                samples: vec![0, 1],
+            })
+        );
+        manifest.verify(dir, &[8]).unwrap();
+        assert_eq!(manifest.source_ids(), (0..8).collect::<Vec<_>>());
+
+        // later queries use the saved result as a sample
+        let chained = write_query("chained", "shared AND NOT F3");
+        let direct = write_query("direct", "F1 AND F2 AND NOT F3");
+        assert_eq!(kmers(&chained), kmers(&direct));
+        // it is not part of the universe, but can be subtracted from it
+        assert_eq!(kmers(&complement), before);
+        let chained = write_query("chained", "NOT (F3 OR shared)");
+        let direct = write_query("direct", "NOT (F3 OR (F1 AND F2))");
+        assert_eq!(kmers(&chained), kmers(&direct));
+
+        let stats = format!("{}/stats.tsv", dir);
+        run_args(&["stats", "-d", dir, "-o", &stats]).unwrap();
+        let content = fs::read_to_string(&stats).unwrap();
+        assert!(content.contains(&format!(
+            "\n8\tshared\t{}\tquery:{}\t",
+            kmers(&shared).len(),
+            shared
+        )));
+
+        // names already taken, and results that cannot be built again
+        for name in ["shared", "F1", "8"] {
+            assert!(matches!(
+                run_args(&["query", &shared, "-d", dir, "-o", &output, "--save", name]),
+                Err(GrimrError::InvalidArgument(_))
+            ));
+        }
+        assert!(matches!(
+            run_args(&["index", "rebuild", "-d", dir, "shared"]),
+            Err(GrimrError::MissingSample(_))
+        ));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_query_sequences() {
+        let dir = "correctness_test/output_query_seq";
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        // a k-mer of F1, and a sequence of 30 k-mers made of it and of 9 bases absent from F1
+        let kmer = "AAAAAAAAAAAAAAACCGCGA";
+        let fasta = format!("{}/transcripts.fa", dir);
+        fs::write(
+            &fasta,
+            format!(">t1 first\n{}\n>t2\n{}{}\n", kmer, kmer, "T".repeat(29)),
+        )
+        .unwrap();
+        let tsv = format!("{}/hits.tsv", dir);
+        run_args(&[
+            "index",
+            "correctness_test/fof.txt",
+            "--query",
+            "correctness_test/query1.txt",
+            "-d",
+            dir,
+        ])
+        .unwrap();
+        run_args(&[
+            "query-seq",
+            &fasta,
+            "-d",
+            dir,
+            "-o",
+            &tsv,
+            "--min-fraction",
+            "0.5",
+        ])
+        .unwrap();
+        // missing cbls are built first
+        assert_eq!(
+            Manifest::load(dir).unwrap().ids(),
+            (0..8).collect::<Vec<_>>()
+        );
+        let content = fs::read_to_string(&tsv).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines[0], "sequence\tkmers\tF1\tF2\tF3\tF4\tF5\tF6\tF7\tF8");
+        assert!(lines[1].starts_with("t1\t1\t1.0000\t"));
+        assert!(lines[2].starts_with("t2\t30\t0\t"));
+        let matrix = query_sequences(&fasta, dir, 1).unwrap();
+        assert!((matrix.fraction(1, 0) - 1.0 / 30.0).abs() < 1e-9);
+        for (j, sample) in matrix.samples.iter().enumerate() {
+            let path = format!("correctness_test/{}.fa", sample);
+            let cbl = create_cbl_from_fasta(&path, DEFAULT_K).unwrap();
+            assert_eq!(
+                matrix.fraction(0, j) == 1.0,
+                cbl.contains_seq(kmer.as_bytes())[0]
+            );
+        }
+        assert!(run_args(&["query-seq", &fasta, "-d", dir, "--min-fraction", "2"]).is_err());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_similarity() {
+        let dir = "correctness_test/output_similarity";
+        let _ = fs::remove_dir_all(dir);
+        run_args(&[
+            "index",
+            "correctness_test/fof.txt",
+            "--query",
+            "correctness_test/query1.txt",
+            "-d",
+            dir,
+        ])
+        .unwrap();
+        let pairs = format!("{}/pairs.tsv", dir);
+        run_args(&["similarity", "-d", dir, "-o", &pairs, "-b", "3"]).unwrap();
+        let content = fs::read_to_string(&pairs).unwrap();
+        assert_eq!(content.lines().count(), 1 + 8 * 7 / 2);
+        // the counts of the F1 and F2 line match a query intersecting them
+        let cbls: Vec<KmerSet> = ["F1", "F2"]
+            .iter()
+            .map(|name| {
+                create_cbl_from_fasta(&format!("correctness_test/{}.fa", name), DEFAULT_K).unwrap()
+            })
+            .collect();
+        let shared = query_cbls(
+            &Query::And(vec![Query::Leaf(0), Query::Leaf(1)]),
+            dir,
+            Batching::new(4, 1),
+        )
+        .unwrap()
+        .count();
+        let line = content
+            .lines()
+            .find(|line| line.starts_with("F1\tF2\t"))
+            .unwrap();
+        let columns: Vec<&str> = line.split('\t').collect();
+        assert_eq!(columns[2], cbls[0].count().to_string());
+        assert_eq!(columns[3], cbls[1].count().to_string());
+        assert_eq!(columns[4], shared.to_string());
+        assert_eq!(
+            columns[5],
+            (cbls[0].count() + cbls[1].count() - shared).to_string()
+        );
+
+        let jaccard = format!("{}/jaccard.tsv", dir);
+        run_args(&["similarity", "-d", dir, "-o", &jaccard, "-f", "jaccard"]).unwrap();
+        let content = fs::read_to_string(&jaccard).unwrap();
+        assert!(content.starts_with("sample\tF1\tF2\tF3\tF4\tF5\tF6\tF7\tF8\nF1\t1.000000\t"));
+        assert_eq!(content.lines().count(), 9);
+        // a saved result is not a sample of the cohort
+        let query_file = format!("{}/both.txt", dir);
+        fs::write(&query_file, "F1 AND F2").unwrap();
+        let result = format!("{}/both.fa", dir);
+        run_args(&[
+            "query",
+            &query_file,
+            "-d",
+            dir,
+            "-o",
+            &result,
+            "--save",
+            "both",
+        ])
+        .unwrap();
+        run_args(&["similarity", "-d", dir, "-o", &jaccard, "-f", "jaccard"]).unwrap();
+        assert_eq!(fs::read_to_string(&jaccard).unwrap(), content);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_unique() {
+        let dir = "correctness_test/output_unique";
+        let _ = fs::remove_dir_all(dir);
+        run_args(&["index", "correctness_test/fof_tags.txt", "-d", dir]).unwrap();
+        let output = format!("{}/unique", dir);
+        run_args(&["unique", "-d", dir, "-o", &output, "-f", "kmers"]).unwrap();
+        let summary = fs::read_to_string(format!("{}/summary.tsv", output)).unwrap();
+        assert_eq!(summary.lines().count(), 1 + 8);
+        // the same k-mers as a query subtracting every other sample
+        let catalog = Manifest::load(dir).unwrap().catalog;
+        for name in ["F1", "F5"] {
+            let id = catalog.by_name(name).unwrap().id;
+            let others = (0..8).filter(|&other| other != id);
+            let query = Query::And(vec![Query::Leaf(id), Query::negate(Query::any_of(others))]);
+            let expected: HashSet<String> = query_cbls(&query, dir, Batching::new(4, 1))
+                .unwrap()
+                .kmers()
+                .map(|kmer| String::from_utf8(kmer).unwrap())
+                .collect();
+            let path = format!("{}/{}.txt", output, name);
+            let content = fs::read_to_string(&path).unwrap();
+            let kmers: HashSet<String> = content.lines().map(|line| line.to_string()).collect();
+            assert_eq!(kmers, expected);
+            assert!(summary.contains(&format!("\n{}\t1\t{}\t{}\n", name, expected.len(), path)));
+        }
+
+        run_args(&["unique", "-d", dir, "-o", &output, "--by-tag", "-f", "cbl"]).unwrap();
+        let summary = fs::read_to_string(format!("{}/summary.tsv", output)).unwrap();
+        let groups: Vec<&str> = summary
+            .lines()
+            .skip(1)
+            .map(|line| line.split('\t').next().unwrap())
+            .collect();
+        assert!(groups.contains(&"core") && groups.contains(&"control"));
+        // the samples without the core tag carry one of the other tags
+        let core = deserialize_cbl(&format!("{}/core.cbl", output), DEFAULT_K).unwrap();
+        let expr = format!("{}/core.txt", dir);
+        fs::write(&expr, "core AND NOT (control OR groupB OR groupC)").unwrap();
+        let query = parse_query_file(&expr, &catalog).unwrap();
+        let expected = query_cbls(&query, dir, Batching::new(4, 1)).unwrap();
+        assert_eq!(
+            core.kmers().collect::<Vec<_>>(),
+            expected.kmers().collect::<Vec<_>>()
+        );
+        assert!(!core.is_empty());
+        let _ = fs::remove_dir_all(dir);
+
+        // names holding a path or colliding once made into file names get files of their own
+        // in the output directory, the summary keeps the names
+        let dir = "correctness_test/output_unique_names";
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let fof = format!("{}/fof.txt", dir);
+        let samples = "correctness_test/F1.fa core/a\ncorrectness_test/F2.fa\ncorrectness_test/../correctness_test/F2.fa core_a\n";
+        fs::write(&fof, samples).unwrap();
+        run_args(&["index", &fof, "-d", dir]).unwrap();
+        let output = format!("{}/unique", dir);
+        for (by_tag, names) in [
+            (
+                false,
+                vec!["F1", "F2", "correctness_test/../correctness_test/F2.fa"],
+            ),
+            (true, vec!["core/a", "core_a"]),
+        ] {
+            let mut args = vec!["unique", "-d", dir, "-o", &output, "-f", "kmers"];
+            if by_tag {
+                args.push("--by-tag");
+            }
+            run_args(&args).unwrap();
+            let summary = fs::read_to_string(format!("{}/summary.tsv", output)).unwrap();
+            let rows: Vec<Vec<&str>> = summary
+                .lines()
+                .skip(1)
+                .map(|line| line.split('\t').collect())
+                .collect();
+            assert_eq!(rows.iter().map(|row| row[0]).collect::<Vec<_>>(), names);
+            let outputs: HashSet<&str> = rows.iter().map(|row| row[3]).collect();
+            assert_eq!(outputs.len(), names.len());
+            for path in outputs {
+                assert_eq!(Path::new(path).parent(), Some(Path::new(&output)));
+                assert!(Path::new(path).is_file());
+            }
+            fs::remove_dir_all(&output).unwrap();
+        }
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_batch() {
+        let dir = "correctness_test/output_batch";
+        let _ = fs::remove_dir_all(dir);
+        run_args(&["index", "correctness_test/fof_tags.txt", "-d", dir]).unwrap();
+        let queries = [
+            ("caseB", "ALL(core) AND groupB AND NOT control"),
+            ("caseC", "ALL(core) AND groupC AND NOT control"),
+            ("labels", "A\tALL\t[0, 1]\nD\tNOT-ANY\t[2, 3]\n"),
+            ("again", "ALL(core) AND groupB AND NOT control"),
+        ];
+        let batch_file = format!("{}/batch.txt", dir);
+        let text: String = queries
+            .iter()
+            .map(|(name, text)| format!(">{}\n# {}\n{}\n", name, name, text))
+            .collect();
+        fs::write(&batch_file, format!("# shared core and controls\n{}", text)).unwrap();
+        let output = format!("{}/results", dir);
+        run_args(&[
+            "batch",
+            &batch_file,
+            "-d",
+            dir,
+            "-o",
+            &output,
+            "-f",
+            "kmers",
+        ])
+        .unwrap();
+        let summary = fs::read_to_string(format!("{}/summary.tsv", output)).unwrap();
+        assert_eq!(summary.lines().count(), 1 + queries.len());
+        // the same k-mers as every query run on its own
+        let catalog = Manifest::load(dir).unwrap().catalog;
+        for (name, text) in queries {
+            let query = parse_query_text(text, name, 0, &catalog).unwrap();
+            let expected: HashSet<String> = query_cbls(&query, dir, Batching::new(4, 1))
+                .unwrap()
+                .kmers()
+                .map(|kmer| String::from_utf8(kmer).unwrap())
+                .collect();
+            let path = format!("{}/{}.txt", output, name);
+            let content = fs::read_to_string(&path).unwrap();
+            let kmers: HashSet<String> = content.lines().map(|line| line.to_string()).collect();
+            assert_eq!(kmers, expected);
+            assert!(summary.contains(&format!("\n{}\t{}\t{}\n", name, expected.len(), path)));
+        }
+        assert!(!fs::read_to_string(format!("{}/caseB.txt", output))
+            .unwrap()
+            .is_empty());
+
+        let batch = |text: &str| {
+            fs::write(&batch_file, text).unwrap();
+            run_args(&["batch", &batch_file, "-d", dir, "-o", &output])
+        };
+        assert!(matches!(
+            batch("core\n>a\ncore\n"),
+            Err(GrimrError::Parse(_))
+        ));
+        assert!(matches!(
+            batch(">a\ncore\n>a\nF1\n"),
+            Err(GrimrError::Parse(_))
+        ));
+        assert!(matches!(batch(">a/b\ncore\n"), Err(GrimrError::Parse(_))));
+        assert!(matches!(batch("# nothing\n"), Err(GrimrError::Parse(_))));
+        match batch(">a\ncore\n>b\nunknown_tag\n") {
+            Err(GrimrError::InvalidQuery(message)) => assert!(message.contains("query 'b'")),
+            other => panic!("unexpected {:?}", other),
+        }
+        // label lines are numbered within the batch file
+        match batch(">a\nA\tALL\t[0,\n") {
+            Err(GrimrError::Parse(message)) => assert!(message.contains("batch.txt:2:")),
+            other => panic!("unexpected {:?}", other),
+        }
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_query_builds_missing_samples() {
+        let test_output_dir = "correctness_test/output_manifest";
+        let _ = fs::remove_dir_all(test_output_dir);
+        let metadata_path = "correctness_test/fof.txt";
+        let catalog = SampleCatalog::from_metadata(metadata_path).unwrap();
+        assert!(query_cbls(&Query::Leaf(0), test_output_dir, Batching::new(4, 1)).is_err());
+        // only samples 0 and 1 are serialized
+        let query = parse_query_file("correctness_test/query1.txt", &catalog).unwrap();
+        create_and_serialize_cbls(
+            &catalog,
+            metadata_path,
+            test_output_dir,
+            &query,
+            DEFAULT_K,
+            &InputFilter::default(),
+            Building::new(1),
+        )
+        .unwrap();
+        assert!(query_cbls(&query, test_output_dir, Batching::new(4, 1)).is_ok());
+        assert_eq!(Manifest::load(test_output_dir).unwrap().ids(), vec![0, 1]);
+        // another query builds the cbls it needs and keeps them
+        let other = parse_query_file("correctness_test/query15.txt", &catalog).unwrap();
+        let expected: Vec<usize> = other.samples().into_iter().collect();
+        let cbl = query_cbls(&other, test_output_dir, Batching::new(4, 1)).unwrap();
+        let ids = Manifest::load(test_output_dir).unwrap().ids();
+        assert!(expected.iter().all(|id| ids.contains(id)));
+        assert_eq!(
+            query_cbls(&other, test_output_dir, Batching::new(4, 1))
+                .unwrap()
+                .count(),
+            cbl.count()
+        );
+        // a sample that is not in the index
+        assert!(query_cbls(&Query::Leaf(99), test_output_dir, Batching::new(4, 1)).is_err());
+        let _ = fs::remove_dir_all(test_output_dir);
+    }
+
+    #[test]
+    fn test_update_index() {
+        let test_output_dir = "correctness_test/output_update";
+        let _ = fs::remove_dir_all(test_output_dir);
+        let index = |list: &[&str]| {
+            let mut args = vec!["index"];
+            args.extend(list);
+            args.extend(["-d", test_output_dir]);
+            run_args(&args)
+        };
+        index(&["correctness_test/fof.txt"]).unwrap();
+        let manifest = Manifest::load(test_output_dir).unwrap();
+        assert_eq!(manifest.ids(), (0..8).collect::<Vec<_>>());
+        let universe =
+            query_cbls(&Query::any_of(0..8), test_output_dir, Batching::new(4, 1)).unwrap();
+
+        // F8 replaced by a copy under a new id, the union is unchanged
+        index(&["remove", "F8"]).unwrap();
+        index(&["add", "correctness_test/F8.fa", "groupC"]).unwrap();
+        // the index directory can also come before the action
+        run_args(&["index", "-d", test_output_dir, "rebuild", "0"]).unwrap();
+        let manifest = Manifest::load(test_output_dir).unwrap();
+        assert_eq!(manifest.ids(), vec![0, 1, 2, 3, 4, 5, 6, 8]);
+        assert_eq!(manifest.catalog.by_tag("groupC"), vec![8]);
+        let query = parse_tag_expr("NOT F1").unwrap();
+        let query = to_query(&query, &manifest.catalog).unwrap();
+        let not_f1 = query_cbls(&query, test_output_dir, Batching::new(4, 1)).unwrap();
+        assert!(!not_f1.is_empty());
+        let union = query_cbls(
+            &Query::any_of(manifest.ids()),
+            test_output_dir,
+            Batching::new(4, 1),
+        );
+        assert_eq!(union.unwrap().count(), universe.count());
+
+        assert!(index(&["remove", "F9"]).is_err());
+        assert!(index(&["rebuild"]).is_err());
+        assert!(index(&["correctness_test/fof.txt", "--k", "31"]).is_err());
+        let _ = fs::remove_dir_all(test_output_dir);
+    }
+
+    #[test]
+    fn test_parallel_and_budgeted_query() {
+        let dir = "correctness_test/output_parallel_query";
+        let _ = fs::remove_dir_all(dir);
+        run_args(&["index", "correctness_test/fof.txt", "-d", dir]).unwrap();
+        let catalog = Manifest::load(dir).unwrap().catalog;
+        let query = to_query(&parse_tag_expr("NOT F1").unwrap(), &catalog).unwrap();
+        let expected = query_cbls(&query, dir, Batching::new(4, 1)).unwrap();
+        assert!(!expected.is_empty());
+        // batches reduced on several threads or within a memory budget give the same k-mers
+        let batchings = [
+            Batching::new(1, 3),
+            Batching::new(2, 3),
+            Batching::new(4, 2),
+            Batching::new(4, 1).with_max_memory(Some(64 << 10)),
+            Batching::new(4, 3).with_max_memory(Some(0)),
+        ];
+        for batching in batchings {
+            let cbl = query_cbls(&query, dir, batching).unwrap();
+            assert_eq!(
+                cbl.kmers().collect::<HashSet<_>>(),
+                expected.kmers().collect::<HashSet<_>>()
+            );
+        }
+        let _ = fs::remove_dir_all(dir);
+    }
 }
@@ -0,0 +1,684 @@
+use crate::catalog::{Provenance, Sample, SampleCatalog};
+use crate::error::{GrimrError, Result};
+use crate::input::{create_cbl_from_input, InputFilter, InputType};
+use crate::kmers::KmerSet;
+use crate::planner::Cardinalities;
+use crate::utils::{replace_file, serialize_cbl};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+// bumped whenever the layout of the index directory or of the cbls changes
+pub const FORMAT_VERSION: u32 = 1;
+
+pub fn cbl_path(index_dir: &str, id: usize) -> PathBuf {
+    Path::new(index_dir).join(format!("{}.cbl", id))
+}
+
+// FNV-1a 64-bit hash of a file, to detect cbls changed or truncated since indexing
+pub fn file_hash(path: &Path) -> Result<String> {
+    hash_file(path).map_err(GrimrError::io(path))
+}
+
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut buffer = [0u8; 1 << 16];
+    let mut hash: u64 = 0xcbf29ce484222325;
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        for &byte in &buffer[..read] {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    Ok(format!("{:016x}", hash))
+}
+
+// one serialized cbl, `{id}.cbl` in the index directory
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CblEntry {
+    pub id: usize,
+    pub kmers: usize,
+    pub hash: String,
+}
+
+// serialize the cbl of a sample from its sequence file or k-mer dump
+fn build_entry(
+    index_dir: &str,
+    sample: &Sample,
+    k: usize,
+    filter: &InputFilter,
+) -> Result<CblEntry> {
+    let cbl = create_cbl_from_input(&sample.path, sample.input, k, filter)?;
+    let path = cbl_path(index_dir, sample.id);
+    serialize_cbl(&cbl, path.to_str().unwrap())?;
+    Ok(CblEntry {
+        id: sample.id,
+        kmers: cbl.count(),
+        hash: file_hash(&path)?,
+    })
+}
+
+// how the cbls of several samples are built: threads build one sample each at a time, and
+// max_in_flight bounds the cbls they hold together
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Building {
+    pub threads: usize,
+    pub max_in_flight: Option<usize>,
+}
+
+impl Building {
+    pub fn new(threads: usize) -> Self {
+        Building {
+            threads,
+            max_in_flight: None,
+        }
+    }
+
+    pub fn with_max_in_flight(self, max_in_flight: Option<usize>) -> Self {
+        Building {
+            max_in_flight,
+            ..self
+        }
+    }
+}
+
+// cbls held by the threads building samples, at most limit at a time
+struct InFlight {
+    limit: usize,
+    count: Mutex<usize>,
+    released: Condvar,
+}
+
+impl InFlight {
+    fn new(limit: Option<usize>) -> Self {
+        InFlight {
+            limit: limit.unwrap_or(usize::MAX).max(1),
+            count: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    // wait until cbls more fit under the limit, a build larger than the limit waits for all the
+    // others, returns the cbls to release
+    fn acquire(&self, cbls: usize) -> usize {
+        let cbls = cbls.min(self.limit);
+        let mut count = self.count.lock().unwrap();
+        while *count + cbls > self.limit {
+            count = self.released.wait(count).unwrap();
+        }
+        *count += cbls;
+        cbls
+    }
+
+    fn release(&self, cbls: usize) {
+        *self.count.lock().unwrap() -= cbls;
+        self.released.notify_all();
+    }
+}
+
+// reported once the cbl of a sample is serialized
+pub struct Built<'a> {
+    pub done: usize,
+    pub total: usize,
+    pub sample: &'a Sample,
+    pub entry: &'a CblEntry,
+    pub elapsed: Duration,
+}
+
+// description of an index directory, written at index time next to the cbls
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format_version: u32,
+    pub tool_version: String,
+    pub k: usize,
+    pub int_type: String,
+    // how the k-mers of the sequence files were filtered, every sample uses the same
+    #[serde(default)]
+    pub filter: InputFilter,
+    pub metadata_file: String,
+    #[serde(flatten)]
+    pub catalog: SampleCatalog,
+    // ids of removed samples are not given again, queries may still refer to them
+    pub next_id: usize,
+    // sorted by id
+    pub cbls: Vec<CblEntry>,
+}
+
+impl Manifest {
+    // an index of the samples of the catalog, none of them built yet
+    pub fn new(k: usize, metadata_file: &str, catalog: &SampleCatalog) -> Result<Self> {
+        Ok(Manifest {
+            format_version: FORMAT_VERSION,
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            k,
+            int_type: KmerSet::new(k)?.int_type().to_string(),
+            filter: InputFilter::default(),
+            metadata_file: metadata_file.to_string(),
+            catalog: catalog.clone(),
+            next_id: catalog.next_id(),
+            cbls: Vec::new(),
+        })
+    }
+
+    // the previous manifest is kept until the new one is written in full
+    pub fn save(&self, index_dir: &str) -> Result<()> {
+        let path = Path::new(index_dir).join(MANIFEST_FILE);
+        replace_file(&path, |writer| {
+            serde_json::to_writer_pretty(writer, self).map_err(|e| GrimrError::io(&path)(e.into()))
+        })
+    }
+
+    // read the manifest and check it can be used by this version
+    pub fn load(index_dir: &str) -> Result<Self> {
+        let path = Path::new(index_dir).join(MANIFEST_FILE);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(GrimrError::FormatVersion(format!(
+                    "'{}' has no {}, it is not an index or was built by an older version, index it again",
+                    index_dir, MANIFEST_FILE
+                )))
+            }
+            Err(e) => return Err(GrimrError::io(&path)(e)),
+        };
+        let manifest: Manifest = serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| GrimrError::Parse(format!("{}: {}", path.display(), e)))?;
+        if manifest.format_version != FORMAT_VERSION {
+            return Err(GrimrError::FormatVersion(format!(
+                "index '{}' has format version {} (written by version {}), this version reads format {}, index it again",
+                index_dir, manifest.format_version, manifest.tool_version, FORMAT_VERSION
+            )));
+        }
+        let int_type = KmerSet::new(manifest.k)?.int_type();
+        if manifest.int_type != int_type {
+            return Err(GrimrError::FormatVersion(format!(
+                "index '{}' stores {}-mers in {}, this version uses {}",
+                index_dir, manifest.k, manifest.int_type, int_type
+            )));
+        }
+        Ok(manifest)
+    }
+
+    // the k of the index, refusing a different k requested on the command line
+    pub fn check_k(&self, requested: Option<usize>) -> Result<usize> {
+        match requested {
+            Some(requested) if requested != self.k => Err(GrimrError::FormatVersion(format!(
+                "index was built with k = {}, not k = {}",
+                self.k, requested
+            ))),
+            _ => Ok(self.k),
+        }
+    }
+
+    // refuse filtering parameters requested on the command line other than the recorded ones
+    pub fn check_filter(&self, requested: Option<&InputFilter>) -> Result<()> {
+        match requested {
+            Some(requested) if *requested != self.filter => Err(GrimrError::FormatVersion(
+                format!("index was built with {}, not {}", self.filter, requested),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn cardinalities(&self) -> Cardinalities {
+        Cardinalities::new(self.cbls.iter().map(|c| (c.id, c.kmers)).collect())
+    }
+
+    // ids of the serialized cbls
+    pub fn ids(&self) -> Vec<usize> {
+        self.cbls.iter().map(|c| c.id).collect()
+    }
+
+    // ids of the serialized cbls of samples read from a file, the union of which is the
+    // universe, results saved from queries are already part of it
+    pub fn source_ids(&self) -> Vec<usize> {
+        self.cbls
+            .iter()
+            .map(|c| c.id)
+            .filter(|&id| self.catalog.get(id).is_some_and(|s| !s.is_derived()))
+            .collect()
+    }
+
+    pub fn has_cbl(&self, id: usize) -> bool {
+        self.cbls.iter().any(|c| c.id == id)
+    }
+
+    // a sample given by its id or its name
+    pub fn find_sample(&self, id_or_name: &str) -> Option<&Sample> {
+        id_or_name
+            .parse::<usize>()
+            .ok()
+            .and_then(|id| self.catalog.get(id))
+            .or_else(|| self.catalog.by_name(id_or_name))
+    }
+
+    // a new sample with a fresh id, its cbl is not built
+    // refused if its file is already indexed or if its name, see SampleCatalog::new_name, is taken
+    pub fn add_sample(&mut self, index_dir: &str, entry: &str, tags: Vec<String>) -> Result<usize> {
+        let (_, path) = InputType::from_entry(entry);
+        if let Some(sample) = self.catalog.sources().find(|s| s.path == path) {
+            return Err(GrimrError::InvalidArgument(format!(
+                "'{}' is already sample {} ({}) of index '{}'",
+                path, sample.id, sample.name, index_dir
+            )));
+        }
+        self.check_new_name(index_dir, &self.catalog.new_name(path))?;
+        let id = self.next_id;
+        self.catalog.push(id, entry, tags);
+        self.next_id += 1;
+        Ok(id)
+    }
+
+    // refuse a name queries could not refer to unambiguously
+    pub fn check_new_name(&self, index_dir: &str, name: &str) -> Result<()> {
+        if name.is_empty()
+            || name.parse::<usize>().is_ok()
+            || name.contains(char::is_whitespace)
+            || self.catalog.resolve(name).is_some()
+        {
+            return Err(GrimrError::InvalidArgument(format!(
+                "cannot name a sample '{}', it is empty, a number, has spaces or is a sample or tag of index '{}'",
+                name, index_dir
+            )));
+        }
+        Ok(())
+    }
+
+    // save the result of a query as a new sample, later queries refer to it by its name
+    pub fn add_derived(
+        &mut self,
+        index_dir: &str,
+        name: &str,
+        query_file: &str,
+        provenance: Provenance,
+        cbl: &KmerSet,
+    ) -> Result<usize> {
+        self.check_new_name(index_dir, name)?;
+        if cbl.k() != self.k {
+            return Err(GrimrError::FormatVersion(format!(
+                "index was built with k = {}, not k = {}",
+                self.k,
+                cbl.k()
+            )));
+        }
+        let id = self.next_id;
+        let path = cbl_path(index_dir, id);
+        serialize_cbl(cbl, path.to_str().unwrap())?;
+        self.insert_entry(CblEntry {
+            id,
+            kmers: cbl.count(),
+            hash: file_hash(&path)?,
+        });
+        self.catalog.push_derived(id, name, query_file, provenance);
+        self.next_id += 1;
+        Ok(id)
+    }
+
+    // serialize the cbl of a sample from its sequence file, replacing any previous one
+    pub fn build_cbl(&mut self, index_dir: &str, id: usize) -> Result<&CblEntry> {
+        let entry = build_entry(index_dir, self.sample(index_dir, id)?, self.k, &self.filter)?;
+        Ok(self.insert_entry(entry))
+    }
+
+    fn sample(&self, index_dir: &str, id: usize) -> Result<&Sample> {
+        self.catalog.get(id).ok_or_else(|| {
+            GrimrError::MissingSample(format!("no sample {} in index '{}'", id, index_dir))
+        })
+    }
+
+    fn insert_entry(&mut self, entry: CblEntry) -> &CblEntry {
+        let position = match self.cbls.binary_search_by_key(&entry.id, |c| c.id) {
+            Ok(position) => {
+                self.cbls[position] = entry;
+                position
+            }
+            Err(position) => {
+                self.cbls.insert(position, entry);
+                position
+            }
+        };
+        &self.cbls[position]
+    }
+
+    // build the cbls of the given samples on up to `threads` threads, each holding the cbls of
+    // one sample at a time: one, or min_abundance layers when reads are filtered by abundance
+    // max_in_flight bounds the cbls held by all the threads, the others wait for room
+    // entries are recorded as they complete and the first error stops the others
+    pub fn build_cbls<F: FnMut(&Built)>(
+        &mut self,
+        index_dir: &str,
+        ids: &[usize],
+        building: Building,
+        mut progress: F,
+    ) -> Result<()> {
+        let samples = ids
+            .iter()
+            .map(|&id| self.sample(index_dir, id).cloned())
+            .collect::<Result<Vec<_>>>()?;
+        let (k, filter) = (self.k, self.filter);
+        let total = samples.len();
+        let next = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let in_flight = InFlight::new(building.max_in_flight);
+        let mut first_error = None;
+        thread::scope(|scope| {
+            let (sender, receiver) = mpsc::channel();
+            for _ in 0..building.threads.clamp(1, total.max(1)) {
+                let sender = sender.clone();
+                let (samples, next, failed, filter) = (&samples, &next, &failed, &filter);
+                let in_flight = &in_flight;
+                scope.spawn(move || loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    if i >= samples.len() || failed.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let cbls = match samples[i].input {
+                        InputType::Sequences => filter.min_abundance.max(1),
+                        _ => 1,
+                    };
+                    let cbls = in_flight.acquire(cbls);
+                    let start = Instant::now();
+                    let result = build_entry(index_dir, &samples[i], k, filter);
+                    in_flight.release(cbls);
+                    if result.is_err() {
+                        failed.store(true, Ordering::Relaxed);
+                    }
+                    if sender.send((i, result, start.elapsed())).is_err() {
+                        break;
+                    }
+                });
+            }
+            drop(sender);
+            for (done, (i, result, elapsed)) in receiver.iter().enumerate() {
+                match result {
+                    Ok(entry) => {
+                        let entry = self.insert_entry(entry);
+                        progress(&Built {
+                            done: done + 1,
+                            total,
+                            sample: &samples[i],
+                            entry,
+                            elapsed,
+                        });
+                    }
+                    Err(e) => {
+                        first_error.get_or_insert(e);
+                    }
+                }
+            }
+        });
+        first_error.map_or(Ok(()), Err)
+    }
+
+    // build the cbls of the given samples that were never serialized, returns their ids
+    pub fn build_missing<F: FnMut(&Built)>(
+        &mut self,
+        index_dir: &str,
+        ids: &[usize],
+        building: Building,
+        progress: F,
+    ) -> Result<Vec<usize>> {
+        let missing: Vec<usize> = ids
+            .iter()
+            .copied()
+            .filter(|&id| !self.has_cbl(id))
+            .collect();
+        self.build_cbls(index_dir, &missing, building, progress)?;
+        Ok(missing)
+    }
+
+    // forget a sample and delete its cbl
+    pub fn remove_sample(&mut self, index_dir: &str, id: usize) -> Result<Sample> {
+        let sample = self.catalog.remove(id).ok_or_else(|| {
+            GrimrError::MissingSample(format!("no sample {} in index '{}'", id, index_dir))
+        })?;
+        if let Ok(position) = self.cbls.binary_search_by_key(&id, |c| c.id) {
+            self.cbls.remove(position);
+            let path = cbl_path(index_dir, id);
+            fs::remove_file(&path).map_err(GrimrError::io(&path))?;
+        }
+        Ok(sample)
+    }
+
+    // check the cbls of the given samples are indexed and unchanged, before deserializing them
+    pub fn verify(&self, index_dir: &str, ids: &[usize]) -> Result<()> {
+        for &id in ids {
+            let entry = self.cbls.iter().find(|c| c.id == id).ok_or_else(|| {
+                GrimrError::MissingSample(format!(
+                    "sample {} has no cbl in index '{}'",
+                    id, index_dir
+                ))
+            })?;
+            let path = cbl_path(index_dir, id);
+            let hash = file_hash(&path)?;
+            if hash != entry.hash {
+                return Err(GrimrError::FormatVersion(format!(
+                    "{} changed since indexing (hash {} instead of {}), index it again",
+                    path.display(),
+                    hash,
+                    entry.hash
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // samples 0 and 1 of the metadata file are built
+    fn build_index(dir: &str) -> Manifest {
+        fs::create_dir_all(dir).unwrap();
+        let catalog = SampleCatalog::from_metadata("test_files/metadata.csv").unwrap();
+        let mut manifest = Manifest::new(21, "test_files/metadata.csv", &catalog).unwrap();
+        for id in [1, 0] {
+            manifest.build_cbl(dir, id).unwrap();
+        }
+        manifest.save(dir).unwrap();
+        manifest
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = "test_files/output_manifest";
+        let manifest = build_index(dir);
+        let loaded = Manifest::load(dir).unwrap();
+        assert_eq!(loaded, manifest);
+        assert_eq!(loaded.int_type, "u64");
+        assert_eq!(loaded.ids(), vec![0, 1]);
+        assert!(loaded.cardinalities().get(1).unwrap() > 0);
+        assert_eq!(loaded.catalog.len(), 6);
+        assert_eq!(loaded.check_k(None).unwrap(), 21);
+        assert!(loaded.check_k(Some(31)).is_err());
+        loaded.verify(dir, &[0, 1]).unwrap();
+        assert!(!Path::new(dir).join("manifest.json.tmp").exists());
+        // a save that fails leaves the previous manifest
+        let mut changed = loaded.clone();
+        changed.next_id += 1;
+        fs::create_dir(Path::new(dir).join("manifest.json.tmp")).unwrap();
+        assert!(changed.save(dir).is_err());
+        assert_eq!(Manifest::load(dir).unwrap(), manifest);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_filtered_index() {
+        let dir = "test_files/output_manifest_filtered";
+        fs::create_dir_all(dir).unwrap();
+        let catalog = SampleCatalog::from_metadata("test_files/metadata.csv").unwrap();
+        let mut manifest = Manifest::new(21, "test_files/metadata.csv", &catalog).unwrap();
+        let filter = InputFilter {
+            min_quality: Some(20),
+            min_abundance: 2,
+        };
+        manifest.filter = filter;
+        let id = manifest
+            .add_sample(dir, "test_files/reads.fq.gz", vec!["reads".to_string()])
+            .unwrap();
+        assert_eq!(manifest.build_cbl(dir, id).unwrap().kmers, 10);
+        manifest.save(dir).unwrap();
+        let loaded = Manifest::load(dir).unwrap();
+        assert_eq!(loaded.filter, filter);
+        assert!(loaded.check_filter(None).is_ok());
+        assert!(loaded.check_filter(Some(&filter)).is_ok());
+        assert!(matches!(
+            loaded.check_filter(Some(&InputFilter::default())),
+            Err(GrimrError::FormatVersion(_))
+        ));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_update_samples() {
+        let dir = "test_files/output_manifest_update";
+        let mut manifest = build_index(dir);
+        assert_eq!(manifest.find_sample("1").unwrap().name, "test2");
+        assert_eq!(manifest.find_sample("test3").unwrap().id, 2);
+        assert!(manifest.find_sample("test9").is_none());
+
+        manifest.remove_sample(dir, 0).unwrap();
+        assert!(!cbl_path(dir, 0).exists());
+        assert_eq!(manifest.ids(), vec![1]);
+        assert!(manifest.find_sample("test1").is_none());
+        assert!(manifest.remove_sample(dir, 0).is_err());
+
+        let id = manifest
+            .add_sample(dir, "test_files/test1.fa", vec![])
+            .unwrap();
+        assert_eq!(id, 6);
+        // a file already indexed
+        assert!(matches!(
+            manifest.add_sample(dir, "test_files/test1.fa", vec![]),
+            Err(GrimrError::InvalidArgument(_))
+        ));
+        assert!(manifest
+            .add_sample(dir, "kmers:test_files/test2.fa", vec![])
+            .is_err());
+        // names taken by a sample or a tag fall back to the path
+        for path in ["other/test2.fa", "other/A.fa"] {
+            let id = manifest.add_sample(dir, path, vec![]).unwrap();
+            assert_eq!(manifest.catalog.get(id).unwrap().name, path);
+            manifest.catalog.remove(id);
+        }
+        let kmers = manifest.build_cbl(dir, id).unwrap().kmers;
+        assert!(kmers > 0);
+        // rebuilding a sample replaces its entry
+        manifest.build_cbl(dir, 1).unwrap();
+        assert_eq!(manifest.ids(), vec![1, 6]);
+        manifest.verify(dir, &[1, 6]).unwrap();
+        assert!(manifest.build_cbl(dir, 0).is_err());
+        assert_eq!(
+            manifest
+                .build_missing(dir, &[1, 2, 6], Building::new(1), |_| {})
+                .unwrap(),
+            vec![2]
+        );
+        assert_eq!(manifest.ids(), vec![1, 2, 6]);
+        assert!(manifest
+            .build_missing(dir, &[0], Building::new(1), |_| {})
+            .is_err());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_build_cbls_in_parallel() {
+        let dir = "test_files/output_manifest_parallel";
+        let serial = build_index(dir);
+        let catalog = serial.catalog.clone();
+        let mut manifest = Manifest::new(21, "test_files/metadata.csv", &catalog).unwrap();
+        let mut reported = Vec::new();
+        manifest
+            .build_cbls(dir, &[5, 4, 3, 2, 1, 0], Building::new(3), |built| {
+                assert_eq!(built.sample.id, built.entry.id);
+                reported.push((built.done, built.total));
+            })
+            .unwrap();
+        assert_eq!(reported, (1..=6).map(|done| (done, 6)).collect::<Vec<_>>());
+        assert_eq!(manifest.ids(), (0..6).collect::<Vec<_>>());
+        // same cbls as a serial build
+        assert_eq!(&manifest.cbls[..2], &serial.cbls[..]);
+        manifest.verify(dir, &manifest.ids()).unwrap();
+        // and with fewer cbls in memory than threads
+        let mut limited = Manifest::new(21, "test_files/metadata.csv", &catalog).unwrap();
+        limited.filter.min_abundance = 2;
+        limited
+            .build_cbls(
+                dir,
+                &[5, 4, 3, 2, 1, 0],
+                Building::new(3).with_max_in_flight(Some(3)),
+                |_| {},
+            )
+            .unwrap();
+        assert_eq!(limited.ids(), manifest.ids());
+
+        let id = manifest
+            .add_sample(dir, "test_files/none.fa", vec![])
+            .unwrap();
+        assert!(matches!(
+            manifest.build_cbls(dir, &[id, 0, 1], Building::new(2), |_| {}),
+            Err(GrimrError::Io { .. })
+        ));
+        assert!(!manifest.has_cbl(id));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_in_flight() {
+        let in_flight = InFlight::new(Some(3));
+        let peak = Mutex::new(0);
+        thread::scope(|scope| {
+            for cbls in [2, 2, 1, 5, 1] {
+                let (in_flight, peak) = (&in_flight, &peak);
+                scope.spawn(move || {
+                    let cbls = in_flight.acquire(cbls);
+                    let count = *in_flight.count.lock().unwrap();
+                    let mut peak = peak.lock().unwrap();
+                    *peak = count.max(*peak);
+                    drop(peak);
+                    thread::sleep(Duration::from_millis(10));
+                    in_flight.release(cbls);
+                });
+            }
+        });
+        // the build of 5 cbls held the 3 allowed
+        assert_eq!(*peak.lock().unwrap(), 3);
+        assert_eq!(*in_flight.count.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_rejected_indexes() {
+        let dir = "test_files/output_manifest_rejected";
+        let mut manifest = build_index(dir);
+        // sample 2 was never serialized
+        assert!(matches!(
+            manifest.verify(dir, &[2]),
+            Err(GrimrError::MissingSample(_))
+        ));
+        // a cbl modified after indexing
+        fs::copy(cbl_path(dir, 1), cbl_path(dir, 0)).unwrap();
+        assert!(manifest.verify(dir, &[1]).is_ok());
+        assert!(manifest.verify(dir, &[0]).is_err());
+
+        manifest.format_version = FORMAT_VERSION + 1;
+        manifest.save(dir).unwrap();
+        assert!(matches!(
+            Manifest::load(dir),
+            Err(GrimrError::FormatVersion(_))
+        ));
+        fs::remove_file(Path::new(dir).join(MANIFEST_FILE)).unwrap();
+        assert!(Manifest::load(dir).is_err());
+        let _ = fs::remove_dir_all(dir);
+    }
+}
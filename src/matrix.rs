@@ -0,0 +1,149 @@
+use crate::catalog::SampleCatalog;
+use crate::error::{GrimrError, Result};
+use crate::kmers::KmerSet;
+use crate::utils::deserialize_cbl;
+use bincode::{DefaultOptions, Options};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+
+// presence of every k-mer of a query result in the samples touched by the query
+// row i holds one bit per sample, packed in bytes, bit j set if samples[j] contains kmers[i]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PresenceMatrix {
+    pub samples: Vec<String>,
+    pub kmers: Vec<Vec<u8>>,
+    rows: Vec<Vec<u8>>,
+}
+
+impl PresenceMatrix {
+    // look up the result k-mers in the cbl of every sample, loaded one at a time
+    pub fn build(
+        result: &KmerSet,
+        index_dir: &str,
+        ids: &[usize],
+        catalog: &SampleCatalog,
+    ) -> Result<Self> {
+        let kmers: Vec<Vec<u8>> = result.kmers().collect();
+        let mut rows = vec![vec![0u8; ids.len().div_ceil(8)]; kmers.len()];
+        for (j, &id) in ids.iter().enumerate() {
+            let cbl = deserialize_cbl(&format!("{}/{}.cbl", index_dir, id), result.k())?;
+            for (kmer, row) in kmers.iter().zip(rows.iter_mut()) {
+                if cbl.contains_seq(kmer)[0] {
+                    row[j / 8] |= 1 << (j % 8);
+                }
+            }
+        }
+        let samples = ids
+            .iter()
+            .map(|&id| match catalog.get(id) {
+                Some(sample) => sample.name.clone(),
+                None => id.to_string(),
+            })
+            .collect();
+        Ok(PresenceMatrix {
+            samples,
+            kmers,
+            rows,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.kmers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.kmers.is_empty()
+    }
+
+    // whether the k-mer of row i is present in the sample of column j
+    pub fn get(&self, i: usize, j: usize) -> bool {
+        self.rows[i][j / 8] & (1 << (j % 8)) != 0
+    }
+
+    // a header with the sample names, then one line per k-mer with a 0/1 column per sample
+    pub fn write_tsv(&self, output_path: &str) -> Result<()> {
+        let file = File::create(output_path).map_err(GrimrError::io(output_path))?;
+        self.write_tsv_to(BufWriter::new(file))
+            .map_err(GrimrError::io(output_path))
+    }
+
+    pub fn write_tsv_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writeln!(writer, "kmer\t{}", self.samples.join("\t"))?;
+        for (i, kmer) in self.kmers.iter().enumerate() {
+            writer.write_all(kmer)?;
+            for j in 0..self.samples.len() {
+                write!(writer, "\t{}", self.get(i, j) as u8)?;
+            }
+            writeln!(writer)?;
+        }
+        writer.flush()
+    }
+
+    // the bit-packed matrix, encoded like the serialized cbls
+    pub fn write_binary(&self, output_path: &str) -> Result<()> {
+        let file = File::create(output_path).map_err(GrimrError::io(output_path))?;
+        self.write_binary_to(BufWriter::new(file))
+            .map_err(|e| match e {
+                GrimrError::Io { path: None, source } => GrimrError::io(output_path)(source),
+                e => e,
+            })
+    }
+
+    pub fn write_binary_to<W: Write>(&self, mut writer: W) -> Result<()> {
+        DefaultOptions::new()
+            .with_varint_encoding()
+            .reject_trailing_bytes()
+            .serialize_into(&mut writer, self)
+            .map_err(|e| match *e {
+                bincode::ErrorKind::Io(e) => GrimrError::from(e),
+                e => GrimrError::Parse(e.to_string()),
+            })?;
+        Ok(writer.flush()?)
+    }
+
+    pub fn read_binary(input_path: &str) -> Result<Self> {
+        let file = File::open(input_path).map_err(GrimrError::io(input_path))?;
+        DefaultOptions::new()
+            .with_varint_encoding()
+            .reject_trailing_bytes()
+            .deserialize_from(BufReader::new(file))
+            .map_err(|e| GrimrError::Parse(format!("{}: {}", input_path, e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{set_of, write_index, X, Y, Z};
+    use std::fs;
+
+    #[test]
+    fn test_build_and_write() {
+        let dir = "test_files/output_matrix";
+        // samples {X, Y}, {X, Z}, {Z}
+        write_index(dir, &[vec![X, Y], vec![X, Z], vec![Z]]);
+        let result = set_of(&[X, Z]);
+        let matrix =
+            PresenceMatrix::build(&result, dir, &[0, 2], &SampleCatalog::default()).unwrap();
+        assert_eq!(matrix.samples, vec!["0", "2"]);
+        assert_eq!(matrix.len(), 2);
+        for (i, kmer) in matrix.kmers.iter().enumerate() {
+            let x = kmer == X.as_bytes();
+            assert_eq!(matrix.get(i, 0), x);
+            assert_eq!(matrix.get(i, 1), !x);
+        }
+
+        let tsv = format!("{}/matrix.tsv", dir);
+        matrix.write_tsv(&tsv).unwrap();
+        let content = fs::read_to_string(&tsv).unwrap();
+        assert!(content.starts_with("kmer\t0\t2\n"));
+        assert!(content.contains(&format!("{}\t1\t0\n", X)));
+        assert!(content.contains(&format!("{}\t0\t1\n", Z)));
+
+        let bin = format!("{}/matrix.bin", dir);
+        matrix.write_binary(&bin).unwrap();
+        assert_eq!(PresenceMatrix::read_binary(&bin).unwrap(), matrix);
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,245 @@
+use crate::catalog::SampleCatalog;
+use crate::query::Query;
+use std::collections::HashMap;
+
+// number of k-mers of every serialized cbl, recorded at index time
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Cardinalities {
+    counts: HashMap<usize, usize>,
+}
+
+impl Cardinalities {
+    pub fn new(counts: HashMap<usize, usize>) -> Self {
+        Cardinalities { counts }
+    }
+
+    pub fn insert(&mut self, id: usize, count: usize) {
+        self.counts.insert(id, count);
+    }
+
+    pub fn get(&self, id: usize) -> Option<usize> {
+        self.counts.get(&id).copied()
+    }
+
+    // upper bound on the size of the union of every sample
+    fn universe(&self) -> Option<usize> {
+        if self.counts.is_empty() {
+            None
+        } else {
+            Some(self.counts.values().sum())
+        }
+    }
+
+    // estimated number of k-mers in the result of a query, None if a sample is unknown
+    pub fn estimate(&self, query: &Query) -> Option<usize> {
+        match query {
+            Query::Leaf(id) => self.get(*id),
+            Query::Not(_) | Query::Quorum { min: 0, .. } => self.universe(),
+            // each k-mer is counted at least min times in the sum of the sample sizes
+            Query::Quorum { min, samples, .. } => {
+                let sum = samples
+                    .iter()
+                    .map(|&id| self.get(id))
+                    .sum::<Option<usize>>()?;
+                Some(sum / min)
+            }
+            Query::Or(queries) => {
+                let sum = queries
+                    .iter()
+                    .map(|q| self.estimate(q))
+                    .sum::<Option<usize>>()?;
+                Some(self.universe().map_or(sum, |universe| sum.min(universe)))
+            }
+            Query::And(queries) => {
+                let anchors: Vec<&Query> = queries.iter().filter(|q| q.is_anchor()).collect();
+                if anchors.is_empty() {
+                    return self.universe();
+                }
+                anchors.iter().filter_map(|q| self.estimate(q)).min()
+            }
+        }
+    }
+}
+
+// nested conjunctions (disjunctions) are merged into their parent so they can be reordered
+fn flatten(queries: Vec<Query>, and: bool) -> Vec<Query> {
+    let mut flat = Vec::with_capacity(queries.len());
+    for query in queries {
+        match query {
+            Query::And(inner) if and => flat.extend(inner),
+            Query::Or(inner) if !and => flat.extend(inner),
+            _ => flat.push(query),
+        }
+    }
+    flat
+}
+
+// reorder a query for the evaluator: in a conjunction the smallest anchor comes first,
+// then every subtraction, then the other operands from the smallest to the largest
+// operands of unknown size keep their relative order after the known ones
+pub fn plan(query: &Query, cardinalities: &Cardinalities) -> Query {
+    match query {
+        Query::Leaf(_) | Query::Quorum { .. } => query.clone(),
+        Query::Not(inner) => match inner.as_ref() {
+            Query::Not(query) => plan(query, cardinalities),
+            _ => Query::negate(plan(inner, cardinalities)),
+        },
+        Query::Or(queries) => Query::Or(flatten(
+            queries.iter().map(|q| plan(q, cardinalities)).collect(),
+            false,
+        )),
+        Query::And(queries) => {
+            let planned = flatten(
+                queries.iter().map(|q| plan(q, cardinalities)).collect(),
+                true,
+            );
+            let (mut negatives, mut positives): (Vec<Query>, Vec<Query>) = planned
+                .into_iter()
+                .partition(|q| matches!(q, Query::Not(_)));
+            positives.sort_by_key(|q| cardinalities.estimate(q).unwrap_or(usize::MAX));
+            negatives.sort_by_key(|q| match q {
+                Query::Not(inner) => cardinalities.estimate(inner).unwrap_or(usize::MAX),
+                _ => unreachable!(),
+            });
+            let mut ordered = Vec::with_capacity(positives.len() + negatives.len());
+            if let Some(first) = positives.iter().position(|q| q.is_anchor()) {
+                ordered.push(positives.remove(first));
+            }
+            ordered.append(&mut negatives);
+            ordered.append(&mut positives);
+            Query::And(ordered)
+        }
+    }
+}
+
+fn sample_label(id: usize, catalog: &SampleCatalog) -> String {
+    match catalog.get(id) {
+        Some(sample) => format!("sample {} ({})", id, sample.name),
+        None => format!("sample {}", id),
+    }
+}
+
+fn explain_into(
+    out: &mut String,
+    query: &Query,
+    depth: usize,
+    cardinalities: &Cardinalities,
+    catalog: &SampleCatalog,
+) {
+    let label = match query {
+        Query::Leaf(id) => sample_label(*id, catalog),
+        Query::And(_) => "AND".to_string(),
+        Query::Or(_) => "OR".to_string(),
+        Query::Not(_) => "NOT".to_string(),
+        Query::Quorum { min, max, .. } => match max {
+            Some(max) => format!("AT LEAST {} AND AT MOST {} OF", min, max),
+            None => format!("AT LEAST {} OF", min),
+        },
+    };
+    let size = match cardinalities.estimate(query) {
+        Some(estimate) => format!("~{} k-mers", estimate),
+        None => "unknown size".to_string(),
+    };
+    out.push_str(&format!("{}{} [{}]\n", "  ".repeat(depth), label, size));
+    match query {
+        Query::Leaf(_) => {}
+        Query::And(queries) | Query::Or(queries) => {
+            for q in queries {
+                explain_into(out, q, depth + 1, cardinalities, catalog);
+            }
+        }
+        Query::Not(inner) => explain_into(out, inner, depth + 1, cardinalities, catalog),
+        Query::Quorum { samples, .. } => {
+            for &id in samples {
+                explain_into(out, &Query::Leaf(id), depth + 1, cardinalities, catalog);
+            }
+        }
+    }
+}
+
+// the plan as an indented tree, operands are applied from top to bottom
+pub fn explain(query: &Query, cardinalities: &Cardinalities, catalog: &SampleCatalog) -> String {
+    let mut out = String::new();
+    explain_into(&mut out, query, 0, cardinalities, catalog);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cardinalities() -> Cardinalities {
+        Cardinalities::new([(0, 1000), (1, 10), (2, 500), (3, 50), (4, 5)].into())
+    }
+
+    #[test]
+    fn test_estimate() {
+        let cards = cardinalities();
+        assert_eq!(cards.estimate(&Query::Leaf(2)), Some(500));
+        assert_eq!(cards.estimate(&Query::all_of([0, 2, 3])), Some(50));
+        assert_eq!(cards.estimate(&Query::any_of([1, 3])), Some(60));
+        assert_eq!(cards.estimate(&Query::negate(Query::Leaf(1))), Some(1565));
+        assert_eq!(cards.estimate(&Query::any_of([1, 7])), None);
+        let quorum = |min| Query::Quorum {
+            min,
+            max: None,
+            samples: vec![1, 2, 3],
+        };
+        assert_eq!(cards.estimate(&quorum(2)), Some(280));
+        assert_eq!(cards.estimate(&quorum(0)), Some(1565));
+        assert_eq!(Cardinalities::default().estimate(&Query::Leaf(0)), None);
+    }
+
+    #[test]
+    fn test_plan_order() {
+        // 0 and (2 or 3) and not 4 and (1 and 3)
+        let query = Query::And(vec![
+            Query::Leaf(0),
+            Query::any_of([2, 3]),
+            Query::negate(Query::Leaf(4)),
+            Query::all_of([1, 3]),
+        ]);
+        let planned = plan(&query, &cardinalities());
+        assert_eq!(
+            planned,
+            Query::And(vec![
+                Query::Leaf(1),
+                Query::negate(Query::Leaf(4)),
+                Query::Leaf(3),
+                Query::any_of([2, 3]),
+                Query::Leaf(0),
+            ])
+        );
+        assert_eq!(query.samples(), planned.samples());
+    }
+
+    #[test]
+    fn test_plan_unknown_sizes() {
+        let query = Query::And(vec![
+            Query::negate(Query::Leaf(1)),
+            Query::Leaf(2),
+            Query::Leaf(0),
+        ]);
+        let planned = plan(&query, &Cardinalities::default());
+        assert_eq!(
+            planned,
+            Query::And(vec![
+                Query::Leaf(2),
+                Query::negate(Query::Leaf(1)),
+                Query::Leaf(0),
+            ])
+        );
+        let double = Query::negate(Query::negate(Query::Leaf(3)));
+        assert_eq!(plan(&double, &cardinalities()), Query::Leaf(3));
+    }
+
+    #[test]
+    fn test_explain() {
+        let query = Query::And(vec![Query::Leaf(0), Query::negate(Query::Leaf(1))]);
+        let text = explain(&query, &cardinalities(), &SampleCatalog::default());
+        assert_eq!(
+            text,
+            "AND [~1000 k-mers]\n  sample 0 [~1000 k-mers]\n  NOT [~1565 k-mers]\n    sample 1 [~10 k-mers]\n"
+        );
+    }
+}
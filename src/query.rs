@@ -0,0 +1,533 @@
+use crate::batch::SharedResults;
+use crate::cache::CblCache;
+use crate::error::Result;
+use crate::kmers::{check_k, KmerSet};
+use crate::reduce::{reduce, Batching, SetOp};
+use crate::utils::deserialize_cbl;
+use std::collections::BTreeSet;
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// boolean formula over samples, a leaf is the id (and cbl number) of a sample
+// NOT is taken relative to the universe, the union of every indexed sample
+// a quorum keeps the k-mers present in at least `min` and at most `max` of its samples
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Query {
+    Leaf(usize),
+    And(Vec<Query>),
+    Or(Vec<Query>),
+    Not(Box<Query>),
+    Quorum {
+        min: usize,
+        max: Option<usize>,
+        samples: Vec<usize>,
+    },
+}
+
+impl Query {
+    // k-mers present in every sample
+    pub fn all_of<I: IntoIterator<Item = usize>>(ids: I) -> Query {
+        let mut queries: Vec<Query> = ids.into_iter().map(Query::Leaf).collect();
+        if queries.len() == 1 {
+            queries.pop().unwrap()
+        } else {
+            Query::And(queries)
+        }
+    }
+
+    // k-mers present in at least one sample
+    pub fn any_of<I: IntoIterator<Item = usize>>(ids: I) -> Query {
+        let mut queries: Vec<Query> = ids.into_iter().map(Query::Leaf).collect();
+        if queries.len() == 1 {
+            queries.pop().unwrap()
+        } else {
+            Query::Or(queries)
+        }
+    }
+
+    pub fn negate(query: Query) -> Query {
+        Query::Not(Box::new(query))
+    }
+
+    // every sample the query refers to
+    pub fn samples(&self) -> BTreeSet<usize> {
+        let mut samples = BTreeSet::new();
+        self.collect_samples(&mut samples);
+        samples
+    }
+
+    fn collect_samples(&self, samples: &mut BTreeSet<usize>) {
+        match self {
+            Query::Leaf(id) => {
+                samples.insert(*id);
+            }
+            Query::And(queries) | Query::Or(queries) => {
+                for query in queries {
+                    query.collect_samples(samples);
+                }
+            }
+            Query::Not(query) => query.collect_samples(samples),
+            Query::Quorum { samples: ids, .. } => samples.extend(ids),
+        }
+    }
+
+    // whether the query can start a conjunction, the other operands only remove k-mers:
+    // a negation, or a quorum allowing k-mers absent from every sample
+    pub fn is_anchor(&self) -> bool {
+        !matches!(self, Query::Not(_) | Query::Quorum { min: 0, .. })
+    }
+
+    // whether the evaluation has to load the universe
+    pub fn needs_universe(&self) -> bool {
+        match self {
+            Query::Leaf(_) => false,
+            Query::Not(_) => true,
+            Query::Quorum { min, .. } => *min == 0,
+            Query::Or(queries) => queries.iter().any(|q| q.needs_universe()),
+            Query::And(queries) => {
+                let (positives, negatives) = split_negations(queries);
+                let anchors: Vec<&&Query> = positives.iter().filter(|q| q.is_anchor()).collect();
+                anchors.is_empty()
+                    || anchors.iter().any(|q| q.needs_universe())
+                    || negatives.iter().any(|q| q.needs_universe())
+            }
+        }
+    }
+}
+
+// operands of a conjunction, and the inner queries of its negated operands
+fn split_negations(queries: &[Query]) -> (Vec<&Query>, Vec<&Query>) {
+    let mut positives = Vec::new();
+    let mut negatives = Vec::new();
+    for query in queries {
+        match query {
+            Query::Not(inner) => negatives.push(inner.as_ref()),
+            _ => positives.push(query),
+        }
+    }
+    (positives, negatives)
+}
+
+// remove the samples at the front of queries[start..]
+fn take_leaf_run(queries: &mut Vec<&Query>, start: usize) -> Vec<usize> {
+    let mut ids = Vec::new();
+    while let Some(Query::Leaf(id)) = queries.get(start) {
+        ids.push(*id);
+        queries.remove(start);
+    }
+    ids
+}
+
+// evaluates queries over the cbls serialized in an index directory, all built with k
+pub struct Evaluator<'a> {
+    index_dir: &'a str,
+    k: usize,
+    universe: &'a [usize],
+    batching: Batching,
+    cache: Option<&'a CblCache>,
+    shared: Option<&'a SharedResults>,
+    // bytes of the copies of cached cbls lent by the cache, given back when the evaluator is dropped
+    lent: AtomicU64,
+}
+
+impl<'a> Evaluator<'a> {
+    pub fn new(
+        index_dir: &'a str,
+        k: usize,
+        universe: &'a [usize],
+        batching: Batching,
+    ) -> Result<Self> {
+        check_k(k)?;
+        Ok(Evaluator {
+            index_dir,
+            k,
+            universe,
+            batching,
+            cache: None,
+            shared: None,
+            lent: AtomicU64::new(0),
+        })
+    }
+
+    // read the cbls through a cache shared between evaluations, such as the ones of a server
+    pub fn with_cache(mut self, cache: &'a CblCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    // keep the results of the subqueries shared by the queries of a batch
+    pub fn with_shared(mut self, shared: &'a SharedResults) -> Self {
+        self.shared = Some(shared);
+        self
+    }
+
+    fn is_shared(&self, query: &Query) -> bool {
+        self.shared.is_some_and(|shared| shared.contains(query))
+    }
+
+    fn path(&self, id: usize) -> String {
+        format!("{}/{}.cbl", self.index_dir, id)
+    }
+
+    fn load(&self, id: usize) -> Result<KmerSet> {
+        match self.cache {
+            // evaluation consumes the cbls it loads, set operations need each operand to itself:
+            // a cbl the cache did not keep is taken as is, a cached one is copied
+            Some(cache) => {
+                let size = self.size(id);
+                let cbl =
+                    cache.get_or_load(id, size, || deserialize_cbl(&self.path(id), self.k))?;
+                Ok(Arc::try_unwrap(cbl).unwrap_or_else(|cbl| {
+                    cache.lend(size);
+                    self.lent.fetch_add(size, Ordering::Relaxed);
+                    KmerSet::clone(&cbl)
+                }))
+            }
+            None => deserialize_cbl(&self.path(id), self.k),
+        }
+    }
+
+    // bytes of a loaded cbl, estimated by the size of its file
+    fn size(&self, id: usize) -> u64 {
+        fs::metadata(self.path(id)).map_or(0, |metadata| metadata.len())
+    }
+
+    // k was checked when the evaluator was created
+    fn empty(&self) -> KmerSet {
+        KmerSet::new(self.k).unwrap()
+    }
+
+    // union or intersection of samples, deserialized in batches as set by batching
+    fn reduce(&self, ids: &[usize], op: SetOp) -> Result<KmerSet> {
+        let load = |&id: &usize| self.load(id);
+        reduce(self.k, ids, self.batching, op, load, |&id| self.size(id))
+    }
+
+    pub fn universe(&self) -> Result<KmerSet> {
+        self.reduce(self.universe, SetOp::Union)
+    }
+
+    pub fn eval(&self, query: &Query) -> Result<KmerSet> {
+        match self.shared {
+            Some(shared) => match shared.take(query) {
+                Some(cbl) => Ok(cbl),
+                None => {
+                    let cbl = self.compute(query)?;
+                    shared.keep(query, &cbl);
+                    Ok(cbl)
+                }
+            },
+            None => self.compute(query),
+        }
+    }
+
+    fn compute(&self, query: &Query) -> Result<KmerSet> {
+        match query {
+            Query::Leaf(id) => self.load(*id),
+            Query::Not(inner) => {
+                let mut global_cbl = self.universe()?;
+                global_cbl -= &mut self.eval(inner)?;
+                Ok(global_cbl)
+            }
+            Query::Or(queries) => self.eval_or(queries),
+            Query::And(queries) => self.eval_and(queries),
+            Query::Quorum { min, max, samples } => self.eval_quorum(*min, *max, samples, None),
+        }
+    }
+
+    // k-mers present in at least min and at most max samples, taken within a set if given
+    // layers[i] holds the k-mers seen in at least i + 1 of the samples loaded so far
+    fn eval_quorum(
+        &self,
+        min: usize,
+        max: Option<usize>,
+        samples: &[usize],
+        within: Option<&mut KmerSet>,
+    ) -> Result<KmerSet> {
+        let n = samples.len();
+        if min > n || max.is_some_and(|max| max < min) {
+            return Ok(self.empty());
+        }
+        let levels = match max {
+            Some(max) if max < n => min.max(max + 1),
+            _ => min,
+        };
+        let mut layers: Vec<KmerSet> = (0..levels).map(|_| self.empty()).collect();
+        let mut within = within;
+        if levels > 0 {
+            for (processed, &id) in samples.iter().enumerate() {
+                let mut cbl = self.load(id)?;
+                if let Some(within) = within.as_deref_mut() {
+                    cbl &= within;
+                }
+                for j in (1..levels.min(processed + 1)).rev() {
+                    let (lower, upper) = layers.split_at_mut(j);
+                    upper[0] |= &mut (&mut lower[j - 1] & &mut cbl);
+                }
+                layers[0] |= &mut cbl;
+            }
+        }
+
+        let mut global_cbl = if min > 0 {
+            std::mem::replace(&mut layers[min - 1], self.empty())
+        } else {
+            match within {
+                Some(within) => std::mem::replace(within, self.empty()),
+                None => self.universe()?,
+            }
+        };
+        if let Some(max) = max {
+            if max < n {
+                global_cbl -= &mut layers[max];
+            }
+        }
+        Ok(global_cbl)
+    }
+
+    fn eval_or(&self, queries: &[Query]) -> Result<KmerSet> {
+        let leaves: Vec<usize> = queries
+            .iter()
+            .filter_map(|q| match q {
+                Query::Leaf(id) => Some(*id),
+                _ => None,
+            })
+            .collect();
+        let mut global_cbl = self.reduce(&leaves, SetOp::Union)?;
+        for query in queries {
+            if !matches!(query, Query::Leaf(_)) {
+                global_cbl |= &mut self.eval(query)?;
+            }
+        }
+        Ok(global_cbl)
+    }
+
+    // operands are applied in order: the first anchor is the starting set, later operands
+    // are intersected, negated ones subtracted, unions and quorums restricted to what is left
+    // runs of consecutive samples are intersected in batches, see planner::plan for the order
+    fn eval_and(&self, queries: &[Query]) -> Result<KmerSet> {
+        let mut rest: Vec<&Query> = queries.iter().collect();
+        let mut global_cbl = match rest.iter().position(|q| q.is_anchor()) {
+            Some(start) => match rest.remove(start) {
+                Query::Leaf(id) => {
+                    let mut ids = vec![*id];
+                    ids.extend(take_leaf_run(&mut rest, start));
+                    self.reduce(&ids, SetOp::Intersection)?
+                }
+                query => self.eval(query)?,
+            },
+            None => self.universe()?,
+        };
+
+        let mut i = 0;
+        while i < rest.len() {
+            if global_cbl.is_empty() {
+                // the uses the operands left would have made of shared results are given up
+                if let Some(shared) = self.shared {
+                    for query in &rest[i..] {
+                        match query {
+                            Query::Not(inner) => shared.skip(inner),
+                            query if query.is_anchor() => shared.skip(query),
+                            _ => {}
+                        }
+                    }
+                }
+                return Ok(global_cbl);
+            }
+            match rest[i] {
+                Query::Leaf(_) => {
+                    let ids = take_leaf_run(&mut rest, i);
+                    global_cbl &= &mut self.reduce(&ids, SetOp::Intersection)?;
+                    continue;
+                }
+                Query::Not(query) => global_cbl -= &mut self.eval(query)?,
+                // computed once for the whole batch rather than within the current result
+                query if query.is_anchor() && self.is_shared(query) => {
+                    global_cbl &= &mut self.eval(query)?
+                }
+                Query::Or(alternatives) => {
+                    global_cbl = self.restricted_union(&mut global_cbl, alternatives)?
+                }
+                Query::Quorum { min, max, samples } => {
+                    global_cbl = self.eval_quorum(*min, *max, samples, Some(&mut global_cbl))?
+                }
+                query => global_cbl &= &mut self.eval(query)?,
+            }
+            i += 1;
+        }
+        Ok(global_cbl)
+    }
+
+    // union of the alternatives, each intersected with the current result first
+    fn restricted_union(
+        &self,
+        global_cbl: &mut KmerSet,
+        alternatives: &[Query],
+    ) -> Result<KmerSet> {
+        if alternatives.is_empty() {
+            return Ok(self.empty());
+        }
+        if self.batching.batch_size > 1 {
+            let mut local_cbl = self.empty();
+            for chunk in alternatives.chunks(self.batching.batch_size) {
+                let mut cbls_chunk = chunk
+                    .iter()
+                    .map(|q| Ok(&mut *global_cbl & &mut self.eval(q)?))
+                    .collect::<Result<Vec<_>>>()?;
+                local_cbl |= &mut KmerSet::merge(self.k, cbls_chunk.iter_mut().collect())?;
+            }
+            Ok(local_cbl)
+        } else {
+            let mut local_cbl = self.empty();
+            for query in alternatives {
+                local_cbl |= &mut (&mut *global_cbl & &mut self.eval(query)?);
+            }
+            Ok(local_cbl)
+        }
+    }
+}
+
+impl Drop for Evaluator<'_> {
+    fn drop(&mut self) {
+        if let Some(cache) = self.cache {
+            cache.give_back(*self.lent.get_mut());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::GrimrError;
+    use crate::fixtures::{kmers, write_index, K, W, X, Y, Z};
+    use std::fs;
+
+    // four samples: {X, Y}, {X, Z}, {W, Z}, {W}
+    fn build_index(dir: &str) -> Vec<usize> {
+        write_index(dir, &[vec![X, Y], vec![X, Z], vec![W, Z], vec![W]]);
+        vec![0, 1, 2, 3]
+    }
+
+    fn expected(kmers: &[&str]) -> BTreeSet<String> {
+        kmers.iter().map(|kmer| kmer.to_string()).collect()
+    }
+
+    #[test]
+    fn test_samples_and_universe() {
+        let query = Query::Or(vec![
+            Query::all_of([0, 1]),
+            Query::And(vec![Query::Leaf(2), Query::negate(Query::Leaf(3))]),
+        ]);
+        assert_eq!(
+            query.samples().into_iter().collect::<Vec<_>>(),
+            vec![0, 1, 2, 3]
+        );
+        assert!(!query.needs_universe());
+        assert!(Query::negate(Query::Leaf(0)).needs_universe());
+        assert!(Query::And(vec![Query::negate(Query::Leaf(0))]).needs_universe());
+        assert!(Query::And(vec![]).needs_universe());
+        assert_eq!(Query::any_of([4]), Query::Leaf(4));
+        let at_most_one = Query::Quorum {
+            min: 0,
+            max: Some(1),
+            samples: vec![0, 1],
+        };
+        assert!(at_most_one.needs_universe());
+        assert!(!Query::And(vec![Query::Leaf(2), at_most_one]).needs_universe());
+    }
+
+    #[test]
+    fn test_eval_nested() {
+        let dir = "test_files/output_query_nested";
+        let universe = build_index(dir);
+        // present in (0 and 1) or in (2 and not 3)
+        let query = Query::Or(vec![
+            Query::all_of([0, 1]),
+            Query::And(vec![Query::Leaf(2), Query::negate(Query::Leaf(3))]),
+        ]);
+        let batchings = [
+            Batching::new(1, 1),
+            Batching::new(4, 1),
+            Batching::new(1, 3),
+            Batching::new(4, 2).with_max_memory(Some(1)),
+        ];
+        for batching in batchings {
+            let evaluator = Evaluator::new(dir, K, &universe, batching).unwrap();
+            let cbl = evaluator.eval(&query).unwrap();
+            assert_eq!(kmers(&cbl), expected(&[X, Z]));
+        }
+        // a second evaluation reads the cached cbls, which are left as they were
+        let cache = CblCache::new(1 << 20);
+        for _ in 0..2 {
+            let evaluator = Evaluator::new(dir, K, &universe, Batching::new(2, 2))
+                .unwrap()
+                .with_cache(&cache);
+            let cbl = evaluator.eval(&query).unwrap();
+            assert_eq!(kmers(&cbl), expected(&[X, Z]));
+            // the copies count against the cache until the evaluator is dropped
+            assert!(cache.stats().lent > 0);
+            drop(evaluator);
+            assert_eq!(cache.stats().lent, 0);
+        }
+        assert_eq!(cache.stats().cbls, 4);
+        assert_eq!(cache.stats().misses, 4);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_eval_negations() {
+        let dir = "test_files/output_query_negations";
+        let universe = build_index(dir);
+        let evaluator = Evaluator::new(dir, K, &universe, Batching::new(2, 2)).unwrap();
+        let cbl = evaluator.eval(&Query::negate(Query::Leaf(0))).unwrap();
+        assert_eq!(kmers(&cbl), expected(&[Z, W]));
+        // (0 or 2) and not (1 and 2)
+        let query = Query::And(vec![
+            Query::any_of([0, 2]),
+            Query::negate(Query::all_of([1, 2])),
+        ]);
+        let cbl = evaluator.eval(&query).unwrap();
+        assert_eq!(kmers(&cbl), expected(&[X, Y, W]));
+        // 3 and (0 or 1) is empty
+        let query = Query::And(vec![Query::Leaf(3), Query::any_of([0, 1])]);
+        assert!(evaluator.eval(&query).unwrap().is_empty());
+        // sample 4 was never serialized
+        assert!(matches!(
+            evaluator.eval(&Query::negate(Query::Leaf(4))),
+            Err(GrimrError::Io { .. })
+        ));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_eval_quorum() {
+        let dir = "test_files/output_query_quorum";
+        let universe = build_index(dir);
+        let evaluator = Evaluator::new(dir, K, &universe, Batching::new(4, 1)).unwrap();
+        let quorum = |min, max| Query::Quorum {
+            min,
+            max,
+            samples: vec![0, 1, 2, 3],
+        };
+        // counts: X 2, Y 1, Z 2, W 2
+        let cbl = evaluator.eval(&quorum(2, None)).unwrap();
+        assert_eq!(kmers(&cbl), expected(&[X, Z, W]));
+        let cbl = evaluator.eval(&quorum(1, Some(1))).unwrap();
+        assert_eq!(kmers(&cbl), expected(&[Y]));
+        let cbl = evaluator.eval(&quorum(0, Some(1))).unwrap();
+        assert_eq!(kmers(&cbl), expected(&[Y]));
+        assert!(evaluator.eval(&quorum(3, None)).unwrap().is_empty());
+        assert!(evaluator.eval(&quorum(2, Some(1))).unwrap().is_empty());
+        // in 0 and in none of 1, 2, 3
+        let query = Query::And(vec![
+            Query::Leaf(0),
+            Query::Quorum {
+                min: 0,
+                max: Some(0),
+                samples: vec![1, 2, 3],
+            },
+        ]);
+        assert_eq!(kmers(&evaluator.eval(&query).unwrap()), expected(&[Y]));
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,373 @@
+use crate::error::{GrimrError, Result};
+use crate::kmers::KmerSet;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SetOp {
+    Union,
+    Intersection,
+}
+
+impl SetOp {
+    fn apply(self, global_cbl: &mut KmerSet, local_cbl: &mut KmerSet) {
+        match self {
+            SetOp::Union => *global_cbl |= local_cbl,
+            SetOp::Intersection => *global_cbl &= local_cbl,
+        }
+    }
+
+    fn apply_all(self, k: usize, cbls: &mut [KmerSet]) -> Result<KmerSet> {
+        match self {
+            SetOp::Union => KmerSet::merge(k, cbls.iter_mut().collect()),
+            SetOp::Intersection => KmerSet::intersect(k, cbls.iter_mut().collect()),
+        }
+    }
+}
+
+// how the cbls of a reduction are loaded: at most batch_size at a time on each of threads
+// threads, and with a memory budget, batches small enough for their cbls to fit in it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Batching {
+    pub batch_size: usize,
+    pub threads: usize,
+    // bytes shared by the threads, each holding the cbls of its batch and its own result
+    pub max_memory: Option<u64>,
+}
+
+impl Batching {
+    pub fn new(batch_size: usize, threads: usize) -> Self {
+        Batching {
+            batch_size,
+            threads,
+            max_memory: None,
+        }
+    }
+
+    pub fn with_max_memory(self, max_memory: Option<u64>) -> Self {
+        Batching { max_memory, ..self }
+    }
+}
+
+// union or intersection of the cbls of items, loaded and combined batch_size at a time
+// with several threads, one chunk per thread is reduced like a single thread would, holding
+// a batch and its own result, then the results of the chunks are combined pairwise in a tree,
+// pairs are fixed by chunk order so the result does not depend on thread scheduling
+// size gives the bytes a cbl takes once loaded, the size of its file is a good estimate
+pub fn reduce<T, F, S>(
+    k: usize,
+    items: &[T],
+    batching: Batching,
+    op: SetOp,
+    load: F,
+    size: S,
+) -> Result<KmerSet>
+where
+    T: Sync,
+    F: Fn(&T) -> Result<KmerSet> + Sync,
+    S: Fn(&T) -> u64 + Sync,
+{
+    if items.is_empty() {
+        return KmerSet::new(k);
+    }
+    let batch_size = batching.batch_size.max(1);
+    let threads = batching.threads.max(1);
+    let reduce_chunk = |chunk: &[T], share: Option<u64>| match share {
+        Some(max_memory) => reduce_budgeted(k, chunk, batch_size, max_memory, op, &load, &size),
+        None => reduce_serial(k, chunk, batch_size, op, &load),
+    };
+    if threads == 1 || items.len() <= batch_size {
+        return reduce_chunk(items, batching.max_memory);
+    }
+
+    // one chunk per thread, each folding its batches into a single result, within its share
+    // of the budget if there is one
+    let (chunks, share) = match batching.max_memory {
+        Some(max_memory) => (
+            split_by_size(items, threads, &size),
+            Some(max_memory / threads as u64),
+        ),
+        None => (items.chunks(items.len().div_ceil(threads)).collect(), None),
+    };
+    let mut partials = parallel_map(chunks, threads, |chunk| reduce_chunk(chunk, share))?;
+    if op == SetOp::Intersection {
+        if let Some(i) = partials.iter().position(|cbl| cbl.is_empty()) {
+            return Ok(partials.swap_remove(i));
+        }
+    }
+    while partials.len() > 1 {
+        let mut pairs = Vec::with_capacity(partials.len() / 2 + 1);
+        let mut iter = partials.into_iter();
+        while let Some(left) = iter.next() {
+            pairs.push((left, iter.next()));
+        }
+        partials = parallel_map(pairs, threads, |(mut left, right)| {
+            if let Some(mut right) = right {
+                op.apply(&mut left, &mut right);
+            }
+            Ok(left)
+        })?;
+    }
+    Ok(partials.pop().unwrap())
+}
+
+fn reduce_serial<T, F>(
+    k: usize,
+    items: &[T],
+    batch_size: usize,
+    op: SetOp,
+    load: &F,
+) -> Result<KmerSet>
+where
+    F: Fn(&T) -> Result<KmerSet>,
+{
+    if items.is_empty() {
+        return KmerSet::new(k);
+    }
+    if batch_size > 1 {
+        let mut global_cbl = KmerSet::new(k)?;
+        for (i, chunk) in items.chunks(batch_size).enumerate() {
+            let mut cbls_chunk = chunk.iter().map(load).collect::<Result<Vec<_>>>()?;
+            let mut local_cbl = op.apply_all(k, &mut cbls_chunk)?;
+            if i == 0 {
+                global_cbl = local_cbl;
+            } else {
+                op.apply(&mut global_cbl, &mut local_cbl);
+            }
+        }
+        Ok(global_cbl)
+    } else {
+        let mut global_cbl = load(&items[0])?;
+        for item in &items[1..] {
+            op.apply(&mut global_cbl, &mut load(item)?);
+        }
+        Ok(global_cbl)
+    }
+}
+
+// like reduce_serial, but each batch only takes the cbls that fit in what the current result
+// leaves of max_memory, at least one; the result is estimated with the bytes per k-mer of
+// the cbls loaded so far
+fn reduce_budgeted<T, F, S>(
+    k: usize,
+    items: &[T],
+    batch_size: usize,
+    max_memory: u64,
+    op: SetOp,
+    load: &F,
+    size: &S,
+) -> Result<KmerSet>
+where
+    F: Fn(&T) -> Result<KmerSet>,
+    S: Fn(&T) -> u64,
+{
+    let mut global_cbl: Option<KmerSet> = None;
+    let (mut loaded_bytes, mut loaded_kmers) = (0u64, 0u64);
+    let mut start = 0;
+    while start < items.len() {
+        let used = global_cbl.as_ref().map_or(0, |cbl| {
+            cbl.count() as u64 * loaded_bytes / loaded_kmers.max(1)
+        });
+        let available = max_memory.saturating_sub(used);
+        let mut end = start + 1;
+        let mut batch_bytes = size(&items[start]);
+        while end < items.len() && end - start < batch_size {
+            batch_bytes += size(&items[end]);
+            if batch_bytes > available {
+                break;
+            }
+            end += 1;
+        }
+        let mut cbls_chunk = items[start..end]
+            .iter()
+            .map(load)
+            .collect::<Result<Vec<_>>>()?;
+        for (item, cbl) in items[start..end].iter().zip(&cbls_chunk) {
+            loaded_bytes += size(item);
+            loaded_kmers += cbl.count() as u64;
+        }
+        let mut local_cbl = if cbls_chunk.len() == 1 {
+            cbls_chunk.pop().unwrap()
+        } else {
+            op.apply_all(k, &mut cbls_chunk)?
+        };
+        drop(cbls_chunk);
+        match global_cbl.as_mut() {
+            Some(global_cbl) => op.apply(global_cbl, &mut local_cbl),
+            None => global_cbl = Some(local_cbl),
+        }
+        start = end;
+    }
+    match global_cbl {
+        Some(global_cbl) => Ok(global_cbl),
+        None => KmerSet::new(k),
+    }
+}
+
+// at most parts contiguous chunks of items, of about the same total size
+fn split_by_size<'t, T, S: Fn(&T) -> u64>(items: &'t [T], parts: usize, size: &S) -> Vec<&'t [T]> {
+    let total: u64 = items.iter().map(size).sum();
+    let target = (total / parts as u64).max(1);
+    let mut chunks = Vec::with_capacity(parts);
+    let (mut start, mut bytes) = (0, 0);
+    for (i, item) in items.iter().enumerate() {
+        bytes += size(item);
+        if bytes >= target && chunks.len() + 1 < parts {
+            chunks.push(&items[start..=i]);
+            start = i + 1;
+            bytes = 0;
+        }
+    }
+    if start < items.len() {
+        chunks.push(&items[start..]);
+    }
+    chunks
+}
+
+// f applied to every item on up to threads workers, results in the order of the items
+// the first error stops the workers that have not started another item yet
+fn parallel_map<T, R, F>(items: Vec<T>, threads: usize, f: F) -> Result<Vec<R>>
+where
+    T: Send,
+    R: Send,
+    F: Fn(T) -> Result<R> + Sync,
+{
+    let total = items.len();
+    let inputs: Vec<Mutex<Option<T>>> = items.into_iter().map(|t| Mutex::new(Some(t))).collect();
+    let outputs: Vec<Mutex<Option<R>>> = (0..total).map(|_| Mutex::new(None)).collect();
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let first_error: Mutex<Option<(usize, GrimrError)>> = Mutex::new(None);
+    thread::scope(|scope| {
+        for _ in 0..threads.clamp(1, total.max(1)) {
+            let (inputs, outputs, next, failed, first_error, f) =
+                (&inputs, &outputs, &next, &failed, &first_error, &f);
+            scope.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= total || failed.load(Ordering::Relaxed) {
+                    break;
+                }
+                let item = inputs[i].lock().unwrap().take().unwrap();
+                match f(item) {
+                    Ok(result) => *outputs[i].lock().unwrap() = Some(result),
+                    Err(e) => {
+                        failed.store(true, Ordering::Relaxed);
+                        // keep the error of the earliest item, as the serial path would
+                        let mut first_error = first_error.lock().unwrap();
+                        if first_error.as_ref().is_none_or(|(j, _)| i < *j) {
+                            *first_error = Some((i, e));
+                        }
+                    }
+                }
+            });
+        }
+    });
+    if let Some((_, e)) = first_error.into_inner().unwrap() {
+        return Err(e);
+    }
+    Ok(outputs
+        .into_iter()
+        .map(|output| output.into_inner().unwrap().unwrap())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::K;
+    use std::collections::BTreeSet;
+
+    // sample i holds the k-mers of the sequences at positions multiple of i + 1
+    fn sample(i: usize) -> Result<KmerSet> {
+        if i == 99 {
+            return Err(GrimrError::MissingSample(i.to_string()));
+        }
+        let mut cbl = KmerSet::new(K)?;
+        for j in (0..24).step_by(i + 1) {
+            let mut seq = vec![b'A'; K];
+            for (pos, base) in seq.iter_mut().enumerate() {
+                if j >> (pos % 5) & 1 == 1 {
+                    *base = b"CGT"[pos % 3];
+                }
+            }
+            cbl.insert_seq(&seq);
+        }
+        Ok(cbl)
+    }
+
+    fn kmers(cbl: &KmerSet) -> BTreeSet<Vec<u8>> {
+        cbl.kmers().collect()
+    }
+
+    #[test]
+    fn test_parallel_matches_serial() {
+        let ids: Vec<usize> = (0..11).collect();
+        for op in [SetOp::Union, SetOp::Intersection] {
+            let ids = if op == SetOp::Intersection {
+                &ids[..3]
+            } else {
+                &ids[..]
+            };
+            let serial = reduce(K, ids, Batching::new(1, 1), op, |&i| sample(i), |_| 0).unwrap();
+            assert!(!serial.is_empty());
+            for batch_size in [1, 2, 4] {
+                for threads in [1, 2, 3, 8] {
+                    let batching = Batching::new(batch_size, threads);
+                    let cbl = reduce(K, ids, batching, op, |&i| sample(i), |_| 0).unwrap();
+                    assert_eq!(kmers(&cbl), kmers(&serial));
+                }
+            }
+        }
+        let none: &[usize] = &[];
+        assert!(reduce(
+            K,
+            none,
+            Batching::new(2, 4),
+            SetOp::Union,
+            |&i| sample(i),
+            |_| 0
+        )
+        .unwrap()
+        .is_empty());
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let ids: Vec<usize> = (0..9).collect();
+        let serial = reduce(
+            K,
+            &ids,
+            Batching::new(1, 1),
+            SetOp::Union,
+            |&i| sample(i),
+            |_| 0,
+        );
+        let serial = kmers(&serial.unwrap());
+        // from no cbl fitting in the budget to all of them
+        for max_memory in [0, 100, 300, 10_000] {
+            for threads in [1, 2, 4] {
+                let batching = Batching::new(4, threads).with_max_memory(Some(max_memory));
+                let size = |&i: &usize| 100 - 10 * i as u64;
+                let cbl = reduce(K, &ids, batching, SetOp::Union, |&i| sample(i), size).unwrap();
+                assert_eq!(kmers(&cbl), serial);
+            }
+        }
+        let sizes = [5, 1, 1, 1, 1, 1];
+        let chunks = split_by_size(&sizes, 2, &|&size: &u64| size);
+        assert_eq!(chunks, vec![&sizes[..1], &sizes[1..]]);
+        assert_eq!(split_by_size(&sizes, 8, &|&size: &u64| size).len(), 6);
+    }
+
+    #[test]
+    fn test_parallel_errors() {
+        let ids = [0, 1, 99, 2, 3, 4];
+        for threads in [1, 4] {
+            for max_memory in [None, Some(10)] {
+                let batching = Batching::new(2, threads).with_max_memory(max_memory);
+                let result = reduce(K, &ids, batching, SetOp::Union, |&i| sample(i), |_| 4);
+                assert!(matches!(result, Err(GrimrError::MissingSample(_))));
+            }
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use crate::catalog::SampleCatalog;
+use crate::error::{GrimrError, Result};
+use crate::utils::deserialize_cbl;
+use needletail::parse_fastx_reader;
+use std::fs::File;
+use std::io::{self, Write};
+
+// a named sequence of a query fasta file
+struct Sequence {
+    name: String,
+    seq: Vec<u8>,
+}
+
+// the sequences of a fasta or fastq file, plain or compressed, named by the first word of
+// their header
+fn read_sequences(path: &str) -> Result<Vec<Sequence>> {
+    let parse_error =
+        |e: needletail::errors::ParseError| GrimrError::Parse(format!("{}: {}", path, e));
+    let file = File::open(path).map_err(GrimrError::io(path))?;
+    let mut reader = parse_fastx_reader(file).map_err(parse_error)?;
+    let mut sequences = Vec::new();
+    while let Some(record) = reader.next() {
+        let record = record.map_err(parse_error)?;
+        let id = String::from_utf8_lossy(record.id()).into_owned();
+        sequences.push(Sequence {
+            name: id.split_whitespace().next().unwrap_or_default().to_string(),
+            seq: record.seq().to_ascii_uppercase(),
+        });
+    }
+    Ok(sequences)
+}
+
+// for every sequence of a fasta file, how many of its k-mers each sample contains
+// a k-mer occurring several times in a sequence is counted every time, as are k-mers with an N
+// which no sample contains
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SequenceMatrix {
+    pub samples: Vec<String>,
+    pub sequences: Vec<String>,
+    // number of k-mers of every sequence, 0 if it is shorter than k
+    pub kmers: Vec<usize>,
+    // found[i][j]: k-mers of sequence i in sample j
+    found: Vec<Vec<usize>>,
+}
+
+impl SequenceMatrix {
+    // look up the k-mers of every sequence in the cbl of every sample, loaded one at a time
+    pub fn build(
+        fasta_path: &str,
+        index_dir: &str,
+        k: usize,
+        ids: &[usize],
+        catalog: &SampleCatalog,
+    ) -> Result<Self> {
+        let sequences = read_sequences(fasta_path)?;
+        let mut found = vec![vec![0; ids.len()]; sequences.len()];
+        for (j, &id) in ids.iter().enumerate() {
+            let cbl = deserialize_cbl(&format!("{}/{}.cbl", index_dir, id), k)?;
+            for (sequence, row) in sequences.iter().zip(found.iter_mut()) {
+                row[j] = cbl
+                    .contains_seq(&sequence.seq)
+                    .into_iter()
+                    .filter(|&present| present)
+                    .count();
+            }
+        }
+        let samples = ids
+            .iter()
+            .map(|&id| match catalog.get(id) {
+                Some(sample) => sample.name.clone(),
+                None => id.to_string(),
+            })
+            .collect();
+        Ok(SequenceMatrix {
+            samples,
+            kmers: sequences
+                .iter()
+                .map(|s| (s.seq.len() + 1).saturating_sub(k))
+                .collect(),
+            sequences: sequences.into_iter().map(|s| s.name).collect(),
+            found,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.sequences.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sequences.is_empty()
+    }
+
+    // fraction of the k-mers of sequence i found in sample j, 0 for a sequence without k-mers
+    pub fn fraction(&self, i: usize, j: usize) -> f64 {
+        match self.kmers[i] {
+            0 => 0.0,
+            kmers => self.found[i][j] as f64 / kmers as f64,
+        }
+    }
+
+    // samples containing some k-mer of sequence i, and at least min_fraction of them
+    pub fn hits(&self, i: usize, min_fraction: f64) -> Vec<usize> {
+        (0..self.samples.len())
+            .filter(|&j| self.found[i][j] > 0 && self.fraction(i, j) >= min_fraction)
+            .collect()
+    }
+
+    // a header with the sample names, then one line per sequence with its number of k-mers
+    // and the fraction found in every sample, written as 0 below min_fraction
+    pub fn write_tsv_to<W: Write>(&self, mut writer: W, min_fraction: f64) -> io::Result<()> {
+        writeln!(writer, "sequence\tkmers\t{}", self.samples.join("\t"))?;
+        for (i, name) in self.sequences.iter().enumerate() {
+            write!(writer, "{}\t{}", name, self.kmers[i])?;
+            let hits = self.hits(i, min_fraction);
+            for j in 0..self.samples.len() {
+                match hits.contains(&j) {
+                    true => write!(writer, "\t{:.4}", self.fraction(i, j))?,
+                    false => write!(writer, "\t0")?,
+                }
+            }
+            writeln!(writer)?;
+        }
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{write_index, K};
+    use std::fs;
+
+    #[test]
+    fn test_build_and_write() {
+        let dir = "test_files/output_search";
+        // 30 bases, 10 k-mers, sample 0 holds all of them and sample 1 the first 4
+        let seq = "GTTGTCTATGCCAGGGCGACGACATTGCGG";
+        write_index(dir, &[vec![seq], vec![&seq[..24]]]);
+        let fasta = format!("{}/queries.fa", dir);
+        fs::write(
+            &fasta,
+            format!(
+                ">full transcript\n{}\n>short\nACGT\n>other\n{}\n",
+                seq,
+                "A".repeat(25)
+            ),
+        )
+        .unwrap();
+        let matrix =
+            SequenceMatrix::build(&fasta, dir, K, &[0, 1], &SampleCatalog::default()).unwrap();
+        assert_eq!(matrix.sequences, vec!["full", "short", "other"]);
+        assert_eq!(matrix.kmers, vec![10, 0, 5]);
+        assert_eq!(matrix.fraction(0, 0), 1.0);
+        assert_eq!(matrix.fraction(0, 1), 0.4);
+        assert_eq!(matrix.fraction(1, 0), 0.0);
+        assert_eq!(matrix.hits(0, 0.5), vec![0]);
+        assert_eq!(matrix.hits(0, 0.0), vec![0, 1]);
+        // a sequence without k-mers is in no sample
+        assert!(matrix.hits(1, 0.0).is_empty());
+        assert!(matrix.hits(2, 0.1).is_empty());
+
+        let mut output = Vec::new();
+        matrix.write_tsv_to(&mut output, 0.5).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "sequence\tkmers\t0\t1\nfull\t10\t1.0000\t0\nshort\t0\t0\t0\nother\t5\t0\t0\n"
+        );
+        assert!(matches!(
+            SequenceMatrix::build(
+                "test_files/none.fa",
+                dir,
+                K,
+                &[0],
+                &SampleCatalog::default()
+            ),
+            Err(GrimrError::Io { .. })
+        ));
+        let _ = fs::remove_dir_all(dir);
+    }
+}
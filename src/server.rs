@@ -0,0 +1,396 @@
+use crate::cache::CblCache;
+use crate::error::{GrimrError, Result};
+use crate::expr::{parse_tag_expr, to_query};
+use crate::manifest::Manifest;
+use crate::planner::plan;
+use crate::query::Evaluator;
+use crate::reduce::Batching;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// larger request bodies are refused
+const MAX_BODY: usize = 1 << 20;
+// larger request lines and headers are refused
+const MAX_HEADER: u64 = 64 << 10;
+// a client that sends or reads nothing for this long is dropped, freeing its worker
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+// body of `POST /query`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct QueryRequest {
+    // a tag expression, as in a query file
+    query: String,
+    // whether to return the k-mers of the result, not only their number
+    #[serde(default)]
+    kmers: bool,
+    // at most this many k-mers are returned
+    #[serde(default)]
+    limit: Option<usize>,
+}
+
+// an HTTP status and a JSON body
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub body: Value,
+}
+
+impl Response {
+    fn ok(body: Value) -> Self {
+        Response { status: 200, body }
+    }
+
+    fn error(status: u16, message: String) -> Self {
+        Response {
+            status,
+            body: json!({ "error": message }),
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            413 => "Payload Too Large",
+            431 => "Request Header Fields Too Large",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+impl From<GrimrError> for Response {
+    fn from(e: GrimrError) -> Self {
+        let status = match e {
+            GrimrError::InvalidQuery(_) | GrimrError::Parse(_) | GrimrError::InvalidArgument(_) => {
+                400
+            }
+            GrimrError::MissingSample(_) => 404,
+            _ => 500,
+        };
+        Response::error(status, e.to_string())
+    }
+}
+
+// answers queries over an index whose manifest is read once, keeping the cbls it loads in a
+// cache shared by the requests
+pub struct Server {
+    index_dir: String,
+    manifest: Manifest,
+    universe: Vec<usize>,
+    cache: CblCache,
+    batching: Batching,
+}
+
+impl Server {
+    // the cbls of the manifest must be built and verified
+    pub fn new(index_dir: &str, manifest: Manifest, cache_size: u64, batching: Batching) -> Self {
+        Server {
+            index_dir: index_dir.to_string(),
+            universe: manifest.source_ids(),
+            manifest,
+            cache: CblCache::new(cache_size),
+            batching,
+        }
+    }
+
+    // GET /stats, GET /samples, POST /query
+    pub fn handle(&self, method: &str, path: &str, body: &[u8]) -> Response {
+        let result = match (method, path) {
+            ("GET", "/stats") => Ok(self.stats()),
+            ("GET", "/samples") => Ok(self.samples()),
+            ("POST", "/query") => self.query(body),
+            (_, "/stats" | "/samples" | "/query") => {
+                return Response::error(405, format!("{} is not allowed on {}", method, path))
+            }
+            _ => return Response::error(404, format!("no endpoint {}", path)),
+        };
+        result.map_or_else(Response::from, Response::ok)
+    }
+
+    fn stats(&self) -> Value {
+        json!({
+            "index": self.index_dir,
+            "k": self.manifest.k,
+            "samples": self.manifest.catalog.len(),
+            "cbls": self.manifest.cbls.len(),
+            "cache": self.cache.stats(),
+        })
+    }
+
+    fn samples(&self) -> Value {
+        let cardinalities = self.manifest.cardinalities();
+        self.manifest
+            .catalog
+            .samples
+            .iter()
+            .map(|sample| {
+                json!({
+                    "id": sample.id,
+                    "name": sample.name,
+                    "tags": sample.tags,
+                    "kmers": cardinalities.get(sample.id),
+                })
+            })
+            .collect()
+    }
+
+    fn query(&self, body: &[u8]) -> Result<Value> {
+        let start = Instant::now();
+        let request: QueryRequest = serde_json::from_slice(body)
+            .map_err(|e| GrimrError::Parse(format!("request body: {}", e)))?;
+        let query = to_query(&parse_tag_expr(&request.query)?, &self.manifest.catalog)?;
+        if let Some(id) = query
+            .samples()
+            .into_iter()
+            .find(|&id| !self.manifest.has_cbl(id))
+        {
+            return Err(GrimrError::MissingSample(format!(
+                "sample {} has no cbl in index '{}'",
+                id, self.index_dir
+            )));
+        }
+        let planned = plan(&query, &self.manifest.cardinalities());
+        let cbl = Evaluator::new(
+            &self.index_dir,
+            self.manifest.k,
+            &self.universe,
+            self.batching,
+        )?
+        .with_cache(&self.cache)
+        .eval(&planned)?;
+        let mut response = json!({ "count": cbl.count() });
+        if request.kmers {
+            let limit = request.limit.unwrap_or(usize::MAX);
+            let kmers: Vec<String> = cbl
+                .kmers()
+                .take(limit)
+                .map(|kmer| String::from_utf8_lossy(&kmer).into_owned())
+                .collect();
+            response["truncated"] = json!(kmers.len() < cbl.count());
+            response["kmers"] = json!(kmers);
+        }
+        response["elapsed_ms"] = json!(start.elapsed().as_millis() as u64);
+        Ok(response)
+    }
+
+    // answer the connections of listener on batching.threads workers, further connections wait
+    // in the backlog of the listener until a worker is free; a failed accept is only reported,
+    // the connection is lost but the server goes on
+    pub fn serve(&self, listener: TcpListener) {
+        let (sender, receiver) = mpsc::sync_channel::<TcpStream>(0);
+        let receiver = Mutex::new(receiver);
+        thread::scope(|scope| {
+            for _ in 0..self.batching.threads.max(1) {
+                let receiver = &receiver;
+                scope.spawn(move || loop {
+                    let stream = match receiver.lock().unwrap().recv() {
+                        Ok(stream) => stream,
+                        Err(_) => break,
+                    };
+                    // the client went away or timed out, nothing to answer
+                    let _ = self.handle_connection(stream);
+                });
+            }
+            // moved here so that the workers stop once it is dropped
+            let sender = sender;
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if sender.send(stream).is_err() {
+                            break;
+                        }
+                    }
+                    // e.g. too many open files or a connection reset before it was accepted
+                    Err(e) => eprintln!("error: failed to accept a connection: {}", e),
+                }
+            }
+        })
+    }
+
+    // one request per connection, closed after the response
+    fn handle_connection(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let response = self.read_request(stream.try_clone()?)?;
+        write_response(stream, &response)
+    }
+
+    // the response to the request read from reader, the request line and headers read up to
+    // MAX_HEADER bytes and the body up to MAX_BODY
+    fn read_request<R: Read>(&self, reader: R) -> io::Result<Response> {
+        let mut reader = BufReader::new(reader).take(MAX_HEADER);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        let mut parts = request_line.split_whitespace();
+        let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap_or(0);
+                }
+            }
+        }
+        if reader.limit() == 0 {
+            return Ok(Response::error(
+                431,
+                format!("request line and headers over {} bytes", MAX_HEADER),
+            ));
+        }
+        if content_length > MAX_BODY {
+            return Ok(Response::error(
+                413,
+                format!("request body over {} bytes", MAX_BODY),
+            ));
+        }
+        let mut body = vec![0; content_length];
+        reader.set_limit(content_length as u64);
+        reader.read_exact(&mut body)?;
+        // the query string is ignored
+        let path = target.split('?').next().unwrap_or("");
+        Ok(self.handle(method, path, &body))
+    }
+}
+
+fn write_response<W: Write>(mut writer: W, response: &Response) -> io::Result<()> {
+    let body = response.body.to_string();
+    write!(
+        writer,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.reason(),
+        body.len(),
+        body
+    )?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::SampleCatalog;
+    use crate::manifest::Building;
+    use std::fs;
+
+    fn build_server(dir: &str) -> Server {
+        fs::create_dir_all(dir).unwrap();
+        let catalog = SampleCatalog::from_metadata("test_files/metadata.csv").unwrap();
+        let mut manifest = Manifest::new(21, "test_files/metadata.csv", &catalog).unwrap();
+        manifest
+            .build_cbls(dir, &[0, 1, 2, 3, 4], Building::new(2), |_| {})
+            .unwrap();
+        Server::new(dir, manifest, 1 << 30, Batching::new(2, 1))
+    }
+
+    #[test]
+    fn test_handle() {
+        let dir = "test_files/output_server_handle";
+        let server = build_server(dir);
+        let query = |body: &str| server.handle("POST", "/query", body.as_bytes());
+
+        let response = query(r#"{"query": "A AND NOT E"}"#);
+        assert_eq!(response.status, 200);
+        let expected = Evaluator::new(dir, 21, &[0, 1, 2, 3, 4], Batching::new(4, 1))
+            .unwrap()
+            .eval(
+                &to_query(
+                    &parse_tag_expr("A AND NOT E").unwrap(),
+                    &server.manifest.catalog,
+                )
+                .unwrap(),
+            )
+            .unwrap();
+        assert_eq!(response.body["count"], json!(expected.count()));
+        assert!(response.body.get("kmers").is_none());
+        // the second time the cbls come from the cache
+        let response = query(r#"{"query": "A AND NOT E", "kmers": true, "limit": 2}"#);
+        assert_eq!(response.body["kmers"].as_array().unwrap().len(), 2);
+        assert_eq!(response.body["truncated"], json!(true));
+        let stats = server.handle("GET", "/stats", b"").body;
+        // samples 0 to 3, the query needs no universe
+        assert_eq!(stats["cache"]["cbls"], json!(4));
+        assert!(stats["cache"]["hits"].as_u64().unwrap() > 0);
+
+        let samples = server.handle("GET", "/samples", b"").body;
+        assert_eq!(samples.as_array().unwrap().len(), 6);
+        assert_eq!(samples[0]["name"], json!("test1"));
+        assert_eq!(samples[5]["kmers"], json!(null));
+
+        assert_eq!(query(r#"{"query": "nothing"}"#).status, 400);
+        assert_eq!(query("not json").status, 400);
+        // sample test6 has no cbl
+        assert_eq!(query(r#"{"query": "test6"}"#).status, 404);
+        assert_eq!(server.handle("GET", "/query", b"").status, 405);
+        assert_eq!(server.handle("GET", "/other", b"").status, 404);
+
+        let request = |text: &[u8]| server.read_request(text).unwrap();
+        let body = r#"{"query": "A AND NOT E"}"#;
+        let response = request(
+            format!(
+                "POST /query HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .as_bytes(),
+        );
+        assert_eq!(response.body["count"], json!(expected.count()));
+        // headers are read up to MAX_HEADER bytes, whether or not they end
+        let header = format!("X-Long: {}\r\n", "a".repeat(MAX_HEADER as usize));
+        for end in ["\r\n", ""] {
+            let text = format!("GET /stats HTTP/1.1\r\n{}{}", header, end);
+            assert_eq!(request(text.as_bytes()).status, 431);
+        }
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_serve() {
+        let dir = "test_files/output_server_serve";
+        let server = build_server(dir);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || server.serve(listener));
+        let request = |text: String| {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(text.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let body = r#"{"query": "ALL(A)", "kmers": true}"#;
+        let response = request(format!(
+            "POST /query HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        ));
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let (_, json) = response.split_once("\r\n\r\n").unwrap();
+        let json: Value = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            json["kmers"].as_array().unwrap().len() as u64,
+            json["count"].as_u64().unwrap()
+        );
+        assert_eq!(json["truncated"], json!(false));
+
+        let response = request("GET /stats?pretty HTTP/1.1\r\n\r\n".to_string());
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\"k\":21"));
+        let response =
+            request("POST /query HTTP/1.1\r\nContent-Length: 99999999\r\n\r\n".to_string());
+        assert!(response.starts_with("HTTP/1.1 413 "));
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,193 @@
+use crate::catalog::SampleCatalog;
+use crate::error::Result;
+use crate::kmers::KmerSet;
+use crate::utils::deserialize_cbl;
+use std::io::{self, Write};
+
+// k-mer counts of every sample and of the intersection of every pair of samples
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimilarityMatrix {
+    pub samples: Vec<String>,
+    pub kmers: Vec<usize>,
+    // intersections[i][j]: k-mers shared by samples i and j, symmetric
+    intersections: Vec<Vec<usize>>,
+}
+
+impl SimilarityMatrix {
+    // intersect every pair of samples, block_size cbls are kept in memory and every other cbl
+    // is loaded once per block
+    pub fn build(
+        index_dir: &str,
+        k: usize,
+        ids: &[usize],
+        catalog: &SampleCatalog,
+        block_size: usize,
+    ) -> Result<Self> {
+        let load = |id: usize| deserialize_cbl(&format!("{}/{}.cbl", index_dir, id), k);
+        let n = ids.len();
+        let mut kmers = vec![0; n];
+        let mut intersections = vec![vec![0; n]; n];
+        // the intersection of two cbls, only counted, is dropped before the next one
+        let mut shared = |i: usize, j: usize, a: &mut KmerSet, b: &mut KmerSet| {
+            let count = (a & b).count();
+            intersections[i][j] = count;
+            intersections[j][i] = count;
+        };
+        for start in (0..n).step_by(block_size.max(1)) {
+            let end = (start + block_size.max(1)).min(n);
+            let mut block = ids[start..end]
+                .iter()
+                .map(|&id| load(id))
+                .collect::<Result<Vec<_>>>()?;
+            for a in 0..block.len() {
+                kmers[start + a] = block[a].count();
+                let (head, tail) = block.split_at_mut(a + 1);
+                for (b, other) in tail.iter_mut().enumerate() {
+                    shared(start + a, start + a + 1 + b, &mut head[a], other);
+                }
+            }
+            for (j, &id) in ids.iter().enumerate().skip(end) {
+                let mut other = load(id)?;
+                for (a, cbl) in block.iter_mut().enumerate() {
+                    shared(start + a, j, cbl, &mut other);
+                }
+            }
+        }
+        for (i, count) in kmers.iter().enumerate() {
+            intersections[i][i] = *count;
+        }
+        let samples = ids
+            .iter()
+            .map(|&id| match catalog.get(id) {
+                Some(sample) => sample.name.clone(),
+                None => id.to_string(),
+            })
+            .collect();
+        Ok(SimilarityMatrix {
+            samples,
+            kmers,
+            intersections,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn intersection(&self, i: usize, j: usize) -> usize {
+        self.intersections[i][j]
+    }
+
+    pub fn union(&self, i: usize, j: usize) -> usize {
+        self.kmers[i] + self.kmers[j] - self.intersections[i][j]
+    }
+
+    // shared k-mers over the k-mers of either sample, 0 for two empty samples
+    pub fn jaccard(&self, i: usize, j: usize) -> f64 {
+        ratio(self.intersection(i, j), self.union(i, j))
+    }
+
+    // fraction of the k-mers of sample i found in sample j, 0 if i is empty
+    pub fn containment(&self, i: usize, j: usize) -> f64 {
+        ratio(self.intersection(i, j), self.kmers[i])
+    }
+
+    // one line per pair of distinct samples with the counts and every measure
+    pub fn write_pairs_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writeln!(
+            writer,
+            "sample1\tsample2\tkmers1\tkmers2\tintersection\tunion\tjaccard\tcontainment1in2\tcontainment2in1"
+        )?;
+        for i in 0..self.len() {
+            for j in i + 1..self.len() {
+                writeln!(
+                    writer,
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{:.6}\t{:.6}\t{:.6}",
+                    self.samples[i],
+                    self.samples[j],
+                    self.kmers[i],
+                    self.kmers[j],
+                    self.intersection(i, j),
+                    self.union(i, j),
+                    self.jaccard(i, j),
+                    self.containment(i, j),
+                    self.containment(j, i)
+                )?;
+            }
+        }
+        writer.flush()
+    }
+
+    // a square matrix of one measure, the value of row i and column j is measure(i, j)
+    pub fn write_matrix_to<W: Write, T: std::fmt::Display>(
+        &self,
+        mut writer: W,
+        measure: impl Fn(&Self, usize, usize) -> T,
+    ) -> io::Result<()> {
+        writeln!(writer, "sample\t{}", self.samples.join("\t"))?;
+        for i in 0..self.len() {
+            write!(writer, "{}", self.samples[i])?;
+            for j in 0..self.len() {
+                write!(writer, "\t{}", measure(self, i, j))?;
+            }
+            writeln!(writer)?;
+        }
+        writer.flush()
+    }
+}
+
+fn ratio(part: usize, whole: usize) -> f64 {
+    match whole {
+        0 => 0.0,
+        whole => part as f64 / whole as f64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{write_index, X, Y, Z};
+    use std::fs;
+
+    #[test]
+    fn test_build_and_write() {
+        let dir = "test_files/output_similarity";
+        // samples {X, Y}, {X, Z}, {X, Y, Z}, {}
+        write_index(dir, &[vec![X, Y], vec![X, Z], vec![X, Y, Z], vec![]]);
+        let ids = [0, 1, 2, 3];
+        let catalog = SampleCatalog::default();
+        let matrix = SimilarityMatrix::build(dir, 21, &ids, &catalog, 2).unwrap();
+        assert_eq!(matrix.kmers, vec![2, 2, 3, 0]);
+        assert_eq!(matrix.intersection(0, 1), 1);
+        assert_eq!(matrix.union(0, 1), 3);
+        assert_eq!(matrix.jaccard(0, 2), 2.0 / 3.0);
+        assert_eq!(matrix.containment(0, 2), 1.0);
+        assert_eq!(matrix.containment(2, 0), 2.0 / 3.0);
+        assert_eq!(matrix.jaccard(3, 3), 0.0);
+        assert_eq!(matrix.containment(3, 0), 0.0);
+        // the same counts whatever the number of cbls in memory
+        for block_size in [1, 3, 4, 10] {
+            let other = SimilarityMatrix::build(dir, 21, &ids, &catalog, block_size).unwrap();
+            assert_eq!(other, matrix);
+        }
+
+        let mut output = Vec::new();
+        matrix.write_pairs_to(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output.lines().count(), 1 + 6);
+        assert!(output.contains("\n0\t2\t2\t3\t2\t3\t0.666667\t1.000000\t0.666667\n"));
+        let mut output = Vec::new();
+        matrix
+            .write_matrix_to(&mut output, SimilarityMatrix::intersection)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "sample\t0\t1\t2\t3\n0\t2\t1\t2\t0\n1\t1\t2\t2\t0\n2\t2\t2\t3\t0\n3\t0\t0\t0\t0\n"
+        );
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,208 @@
+use crate::catalog::SampleCatalog;
+use crate::error::Result;
+use crate::kmers::KmerSet;
+use crate::utils::deserialize_cbl;
+use std::collections::HashSet;
+use std::io::{self, Write};
+
+// samples whose private k-mers are extracted together, a single sample or the samples of a tag
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Group {
+    pub name: String,
+    pub ids: Vec<usize>,
+}
+
+// one group per sample
+pub fn sample_groups(ids: &[usize], catalog: &SampleCatalog) -> Vec<Group> {
+    ids.iter()
+        .map(|&id| Group {
+            name: catalog
+                .get(id)
+                .map_or(id.to_string(), |sample| sample.name.clone()),
+            ids: vec![id],
+        })
+        .collect()
+}
+
+// one group per tag carried by some of the samples, samples without a tag are in none
+pub fn tag_groups(ids: &[usize], catalog: &SampleCatalog) -> Vec<Group> {
+    catalog
+        .tags()
+        .into_iter()
+        .map(|tag| Group {
+            name: tag.to_string(),
+            ids: catalog
+                .by_tag(tag)
+                .into_iter()
+                .filter(|id| ids.contains(id))
+                .collect(),
+        })
+        .filter(|group| !group.ids.is_empty())
+        .collect()
+}
+
+// the k-mers of every group found in no sample of ids outside the group, reading every cbl once
+// k-mers seen for the first time are candidates of the groups of their sample, and a sample
+// removes its k-mers from the candidates of the groups it is not in
+pub fn unique_kmers(
+    index_dir: &str,
+    k: usize,
+    ids: &[usize],
+    groups: &[Group],
+) -> Result<Vec<KmerSet>> {
+    let mut seen = KmerSet::new(k)?;
+    let mut unique = groups
+        .iter()
+        .map(|_| KmerSet::new(k))
+        .collect::<Result<Vec<_>>>()?;
+    for &id in ids {
+        let mut cbl = deserialize_cbl(&format!("{}/{}.cbl", index_dir, id), k)?;
+        let mut new = cbl.clone();
+        new -= &mut seen;
+        for (group, kmers) in groups.iter().zip(unique.iter_mut()) {
+            if group.ids.contains(&id) {
+                *kmers |= &mut new;
+            } else if !kmers.is_empty() {
+                *kmers -= &mut cbl;
+            }
+        }
+        seen |= &mut cbl;
+    }
+    Ok(unique)
+}
+
+// a distinct file name for every group, without extension: its name with every character but
+// letters, digits, '-', '_' and inner '.' replaced by '_', or "_" if empty, then "_2", "_3"...
+// if already taken
+pub fn file_names(groups: &[Group]) -> Vec<String> {
+    let mut taken = HashSet::new();
+    groups
+        .iter()
+        .map(|group| {
+            let mut base: String = group
+                .name
+                .char_indices()
+                .map(|(i, c)| match c {
+                    '.' if i > 0 => c,
+                    c if c.is_ascii_alphanumeric() || c == '-' || c == '_' => c,
+                    _ => '_',
+                })
+                .collect();
+            if base.is_empty() {
+                base.push('_');
+            }
+            let mut name = base.clone();
+            let mut n = 1;
+            while !taken.insert(name.clone()) {
+                n += 1;
+                name = format!("{}_{}", base, n);
+            }
+            name
+        })
+        .collect()
+}
+
+// one line per group with its number of samples and of unique k-mers, and where they were written
+pub fn write_summary<W: Write>(
+    mut writer: W,
+    groups: &[Group],
+    unique: &[KmerSet],
+    outputs: &[String],
+) -> io::Result<()> {
+    writeln!(writer, "group\tsamples\tunique_kmers\toutput")?;
+    for ((group, kmers), output) in groups.iter().zip(unique).zip(outputs) {
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}",
+            group.name,
+            group.ids.len(),
+            kmers.count(),
+            output
+        )?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{kmers, set_of, write_index, W, X, Y, Z};
+    use std::collections::BTreeSet;
+    use std::fs;
+
+    fn expected(list: &[&str]) -> BTreeSet<String> {
+        kmers(&set_of(list))
+    }
+
+    #[test]
+    fn test_unique_kmers() {
+        let dir = "test_files/output_unique";
+        // samples {X, Y}, {X, Z}, {Z, W}, {W}
+        write_index(dir, &[vec![X, Y], vec![X, Z], vec![Z, W], vec![W]]);
+        let ids = [0, 1, 2, 3];
+        let catalog = SampleCatalog::default();
+        let groups = sample_groups(&ids, &catalog);
+        assert_eq!(groups[2].name, "2");
+        let unique = unique_kmers(dir, 21, &ids, &groups).unwrap();
+        assert_eq!(kmers(&unique[0]), expected(&[Y]));
+        assert!(unique[1].is_empty());
+        assert!(unique[2].is_empty());
+        assert!(unique[3].is_empty());
+        // a k-mer shared by the samples of a group is still unique to the group
+        let groups = vec![
+            Group {
+                name: "first".to_string(),
+                ids: vec![0, 1],
+            },
+            Group {
+                name: "last".to_string(),
+                ids: vec![2, 3],
+            },
+            Group {
+                name: "middle".to_string(),
+                ids: vec![1, 2],
+            },
+        ];
+        let unique = unique_kmers(dir, 21, &ids, &groups).unwrap();
+        assert_eq!(kmers(&unique[0]), expected(&[X, Y]));
+        assert_eq!(kmers(&unique[1]), expected(&[W]));
+        assert_eq!(kmers(&unique[2]), expected(&[Z]));
+
+        let mut output = Vec::new();
+        let outputs = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        write_summary(&mut output, &groups, &unique, &outputs).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "group\tsamples\tunique_kmers\toutput\nfirst\t2\t2\ta\nlast\t2\t1\tb\nmiddle\t2\t1\tc\n"
+        );
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_file_names() {
+        let group = |name: &str| Group {
+            name: name.to_string(),
+            ids: vec![0],
+        };
+        let groups: Vec<Group> = ["F1", "other/F1.fa", "other_F1.fa", "../up", "", "F1 (2)"]
+            .iter()
+            .map(|name| group(name))
+            .collect();
+        assert_eq!(
+            file_names(&groups),
+            vec!["F1", "other_F1.fa", "other_F1.fa_2", "_._up", "_", "F1__2_"]
+        );
+    }
+
+    #[test]
+    fn test_tag_groups() {
+        let catalog = SampleCatalog::from_metadata("test_files/metadata.csv").unwrap();
+        let groups = tag_groups(&[0, 1, 2, 3], &catalog);
+        let a = groups.iter().find(|group| group.name == "A").unwrap();
+        assert_eq!(a.ids, vec![0, 1, 2]);
+        // samples 4 and 5 are left out
+        assert!(groups
+            .iter()
+            .all(|group| group.ids.iter().all(|&id| id < 4)));
+    }
+}
@@ -0,0 +1,173 @@
+use crate::kmers::KmerSet;
+use std::io::{self, Write};
+
+const BASES: [u8; 4] = *b"ACGT";
+
+// the k-mers of a set as a de Bruijn graph, bidirected if the set stores a k-mer and its
+// reverse complement as one, read from the set itself
+struct Graph<'a> {
+    k: usize,
+    cbl: &'a KmerSet,
+}
+
+// whether a k-mer is in a set
+fn contains(cbl: &KmerSet, kmer: &[u8]) -> bool {
+    cbl.contains_seq(kmer).first() == Some(&true)
+}
+
+// add a k-mer to the visited ones, false if it was already there
+fn visit(visited: &mut KmerSet, kmer: &[u8]) -> bool {
+    if contains(visited, kmer) {
+        return false;
+    }
+    visited.insert_seq(kmer);
+    true
+}
+
+impl<'a> Graph<'a> {
+    // k-mers of the set following kmer, or preceding it if not forward
+    fn neighbours(&self, kmer: &[u8], forward: bool) -> Vec<Vec<u8>> {
+        BASES
+            .iter()
+            .map(|&base| {
+                if forward {
+                    let mut next = kmer[1..].to_vec();
+                    next.push(base);
+                    next
+                } else {
+                    let mut previous = vec![base];
+                    previous.extend_from_slice(&kmer[..self.k - 1]);
+                    previous
+                }
+            })
+            .filter(|neighbour| contains(self.cbl, neighbour))
+            .collect()
+    }
+
+    // k-mers added to a unitig from start, in one direction, while the path does not branch
+    fn extend(&self, start: &[u8], forward: bool, visited: &mut KmerSet) -> Vec<Vec<u8>> {
+        let mut path = Vec::new();
+        let mut current = start.to_vec();
+        loop {
+            let mut next = self.neighbours(&current, forward);
+            if next.len() != 1 {
+                break;
+            }
+            let next = next.pop().unwrap();
+            if self.neighbours(&next, !forward).len() != 1 || !visit(visited, &next) {
+                break;
+            }
+            path.push(next.clone());
+            current = next;
+        }
+        path
+    }
+}
+
+// maximal non-branching paths of the de Bruijn graph of the set, every k-mer in exactly one
+// besides the set, only the visited k-mers are kept, in a set of the same kind
+pub fn unitigs(cbl: &KmerSet) -> Vec<Vec<u8>> {
+    let graph = Graph { k: cbl.k(), cbl };
+    // the k of an existing set is valid
+    let mut visited = KmerSet::new(cbl.k()).unwrap();
+    let mut unitigs = Vec::new();
+    for kmer in cbl.kmers() {
+        if !visit(&mut visited, &kmer) {
+            continue;
+        }
+        let before = graph.extend(&kmer, false, &mut visited);
+        let after = graph.extend(&kmer, true, &mut visited);
+        let mut path = before.iter().rev().chain([&kmer]).chain(&after);
+        let mut unitig = path.next().unwrap().clone();
+        unitig.extend(path.map(|next| next[graph.k - 1]));
+        unitigs.push(unitig);
+    }
+    unitigs
+}
+
+// one record per unitig, its number of k-mers and length in the header
+pub fn write_unitigs<W: Write>(cbl: &KmerSet, mut writer: W) -> io::Result<()> {
+    let k = cbl.k();
+    for (index, unitig) in unitigs(cbl).iter().enumerate() {
+        writeln!(
+            writer,
+            ">unitig{} kmers={} length={}",
+            index,
+            unitig.len() + 1 - k,
+            unitig.len()
+        )?;
+        writer.write_all(unitig)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{set_of, K};
+
+    fn reverse_complement(seq: &[u8]) -> Vec<u8> {
+        seq.iter()
+            .rev()
+            .map(|base| match base {
+                b'A' => b'T',
+                b'C' => b'G',
+                b'G' => b'C',
+                b'T' => b'A',
+                other => *other,
+            })
+            .collect()
+    }
+
+    // every k-mer of the set is in exactly one unitig
+    fn check_cover(cbl: &KmerSet, unitigs: &[Vec<u8>]) {
+        let total: usize = unitigs.iter().map(|u| u.len() + 1 - K).sum();
+        assert_eq!(total, cbl.count());
+        let rebuilt = set_of(
+            &unitigs
+                .iter()
+                .map(|u| std::str::from_utf8(u).unwrap())
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(
+            rebuilt.kmers().collect::<Vec<_>>(),
+            cbl.kmers().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_single_path() {
+        let seq = "GTTGTCTATGCCAGGGCGACGACATTGCGG";
+        let cbl = set_of(&[seq]);
+        let unitigs = unitigs(&cbl);
+        assert_eq!(unitigs.len(), 1);
+        let unitig = unitigs[0].clone();
+        assert!(unitig == seq.as_bytes() || unitig == reverse_complement(seq.as_bytes()));
+        check_cover(&cbl, &unitigs);
+
+        let mut output = Vec::new();
+        write_unitigs(&cbl, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with(">unitig0 kmers=10 length=30\n"));
+        assert!(write_unitigs(&KmerSet::new(K).unwrap(), io::sink()).is_ok());
+    }
+
+    #[test]
+    fn test_branches() {
+        // two sequences sharing their first 25 bases, the shared k-mers end at the fork
+        let shared = "TATACCTGAATGTACGAAACATAAA";
+        let a = format!("{}CCGTA", shared);
+        let b = format!("{}GGTCA", shared);
+        let cbl = set_of(&[&a, &b]);
+        let unitigs = unitigs(&cbl);
+        assert_eq!(unitigs.len(), 3);
+        let mut lengths: Vec<usize> = unitigs.iter().map(|u| u.len()).collect();
+        lengths.sort();
+        assert_eq!(lengths, vec![25, 25, 25]);
+        check_cover(&cbl, &unitigs);
+
+        let reads = crate::utils::create_cbl_from_fasta("test_files/test1.fa", K).unwrap();
+        check_cover(&reads, &super::unitigs(&reads));
+    }
+}
@@ -1,74 +1,111 @@
 #![allow(incomplete_features)]
 #![allow(clippy::type_complexity)]
 
-use bincode::{DefaultOptions, Options};
-use cbl::kmer::Kmer;
-use cbl::CBL;
-use needletail::parse_fastx_file;
+use crate::error::{GrimrError, Result};
+use crate::input::{create_cbl_from_reads, InputFilter};
+use crate::kmers::KmerSet;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use std::fs::{self, File};
-use std::io;
-use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
 
-type T = u64;
-const K: usize = 21;
+// write path through path.tmp, renamed over path once flushed and synced, so that a failed or
+// interrupted write leaves the previous file as it was
+pub fn replace_file<P: AsRef<Path>>(
+    path: P,
+    write: impl FnOnce(&mut BufWriter<File>) -> Result<()>,
+) -> Result<()> {
+    let path = path.as_ref();
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let result = File::create(&tmp)
+        .map_err(GrimrError::io(&tmp))
+        .and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            write(&mut writer)?;
+            let file = writer
+                .into_inner()
+                .map_err(|e| GrimrError::io(&tmp)(e.into_error()))?;
+            file.sync_all().map_err(GrimrError::io(&tmp))
+        })
+        .and_then(|_| fs::rename(&tmp, path).map_err(GrimrError::io(path)));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
 
-// deserialize a given CBL
-pub fn deserialize_cbl(input_filename: &str) -> CBL<K, T> {
+// deserialize a given CBL built with k-mers of length k
+pub fn deserialize_cbl(input_filename: &str, k: usize) -> Result<KmerSet> {
     //let input_filename = format!("{}/{}.cbl", output_dir, input_index);
-    let index =
-        File::open(input_filename).unwrap_or_else(|_| panic!("Failed to open {}", input_filename));
+    let index = File::open(input_filename).map_err(GrimrError::io(input_filename))?;
     let reader = BufReader::new(index);
-    DefaultOptions::new()
-        .with_varint_encoding()
-        .reject_trailing_bytes()
-        .deserialize_from(reader)
-        .unwrap()
+    KmerSet::deserialize_from(k, reader).map_err(|e| match e {
+        GrimrError::Parse(message) => GrimrError::Parse(format!("{}: {}", input_filename, message)),
+        e => e,
+    })
 }
 
-pub fn serialize_cbl(cbl: &CBL<K, T>, output_filename: &str) {
-    let _ = fs::remove_file(&output_filename);
-    let output = File::create(output_filename).unwrap();
-    let mut writer = BufWriter::new(output);
-    DefaultOptions::new()
-        .with_varint_encoding()
-        .reject_trailing_bytes()
-        .serialize_into(&mut writer, cbl)
-        .unwrap();
+// the previous cbl is kept until the new one is written in full
+pub fn serialize_cbl(cbl: &KmerSet, output_filename: &str) -> Result<()> {
+    replace_file(output_filename, |writer| cbl.serialize_into(writer))
 }
 
-pub fn create_cbl_from_fasta(input_filename: &str) -> CBL<K, T> {
-    let mut reader = parse_fastx_file(input_filename).unwrap();
-    let mut cbl = CBL::<K, T>::new();
-    while let Some(record) = reader.next() {
-        let seqrec = record.expect("Invalid record");
-        cbl.insert_seq(&seqrec.seq());
-    }
-    cbl
+// every k-mer of a fasta or fastq file, see input::create_cbl_from_reads to filter them
+pub fn create_cbl_from_fasta(input_filename: &str, k: usize) -> Result<KmerSet> {
+    create_cbl_from_reads(input_filename, k, &InputFilter::default())
 }
 
-pub fn cbl_printer(cbl: &CBL<K, T>, output_path: &str) -> std::io::Result<()> {
-    if cbl.is_empty() {
-        println!("Empty solution.");
-        return Ok(());
-    }
-    let file = File::create(output_path)?;
-    let mut writer = BufWriter::new(file);
-    for (index, kmer) in cbl.iter().enumerate() {
+// write the k-mers as fasta to a file, empty if there is none
+pub fn cbl_printer(cbl: &KmerSet, output_path: &str) -> Result<()> {
+    let file = File::create(output_path).map_err(GrimrError::io(output_path))?;
+    write_fasta(cbl, BufWriter::new(file), output_path)
+}
+
+fn fasta_records<W: Write>(cbl: &KmerSet, mut writer: W) -> io::Result<()> {
+    for (index, kmer) in cbl.kmers().enumerate() {
         writeln!(writer, ">kmer{}", index)?;
-        writer.write_all(&kmer.to_nucs())?;
+        writer.write_all(&kmer)?;
         writer.write_all(b"\n")?;
     }
-    Ok(())
+    writer.flush()
+}
+
+// one `>kmerN` record per k-mer, path names the destination in errors
+pub fn write_fasta<W: Write>(cbl: &KmerSet, writer: W, path: &str) -> Result<()> {
+    fasta_records(cbl, writer).map_err(GrimrError::io(path))
+}
+
+// the fasta records of write_fasta, gzip-compressed
+pub fn write_fasta_gz<W: Write>(cbl: &KmerSet, writer: W, path: &str) -> Result<()> {
+    let mut encoder = GzEncoder::new(writer, Compression::default());
+    fasta_records(cbl, &mut encoder)
+        .and_then(|_| encoder.finish()?.flush())
+        .map_err(GrimrError::io(path))
+}
+
+// one k-mer per line
+pub fn write_kmers<W: Write>(cbl: &KmerSet, mut writer: W, path: &str) -> Result<()> {
+    let mut write = || {
+        for kmer in cbl.kmers() {
+            writer.write_all(&kmer)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()
+    };
+    write().map_err(GrimrError::io(path))
 }
 
-pub fn read_fof_file_csv(file_path: &str) -> io::Result<(Vec<String>, usize)> {
-    let file = File::open(file_path)?;
+pub fn read_fof_file_csv(file_path: &str) -> Result<(Vec<String>, usize)> {
+    let file = File::open(file_path).map_err(GrimrError::io(file_path))?;
     let reader = BufReader::new(file);
     let mut file_paths = Vec::new();
     let mut color_number = 0;
 
     for line in reader.lines() {
-        let line = line?;
+        let line = line.map_err(GrimrError::io(file_path))?;
         if let Some(first_column) = line.split_whitespace().next() {
             file_paths.push(first_column.to_string());
             color_number += 1;
@@ -77,3 +114,68 @@ pub fn read_fof_file_csv(file_path: &str) -> io::Result<(Vec<String>, usize)> {
 
     Ok((file_paths, color_number))
 }
+
+// a number of bytes, with an optional K, M, G or T suffix for powers of 1024
+pub fn parse_size(value: &str) -> Result<u64> {
+    let invalid = || GrimrError::InvalidArgument(format!("invalid size '{}'", value));
+    let value = value.trim();
+    let (digits, shift) = match value.char_indices().last() {
+        Some((i, unit)) if unit.is_ascii_alphabetic() => {
+            let shift = match unit.to_ascii_uppercase() {
+                'K' => 10,
+                'M' => 20,
+                'G' => 30,
+                'T' => 40,
+                _ => return Err(invalid()),
+            };
+            (&value[..i], shift)
+        }
+        _ => (value, 0),
+    };
+    let number: u64 = digits.parse().map_err(|_| invalid())?;
+    number
+        .checked_shl(shift)
+        .filter(|bytes| bytes >> shift == number)
+        .ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{kmers, set_of, X, Y};
+
+    #[test]
+    fn test_serialize_cbl() {
+        let dir = "test_files/output_utils_serialize";
+        fs::create_dir_all(dir).unwrap();
+        let path = format!("{}/0.cbl", dir);
+        serialize_cbl(&set_of(&[X]), &path).unwrap();
+        serialize_cbl(&set_of(&[X, Y]), &path).unwrap();
+        assert_eq!(
+            kmers(&deserialize_cbl(&path, 21).unwrap()),
+            kmers(&set_of(&[X, Y]))
+        );
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+        // a write that fails leaves the previous cbl
+        fs::create_dir(format!("{}.tmp", path)).unwrap();
+        assert!(serialize_cbl(&set_of(&[Y]), &path).is_err());
+        assert_eq!(
+            kmers(&deserialize_cbl(&path, 21).unwrap()),
+            kmers(&set_of(&[X, Y]))
+        );
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("2K").unwrap(), 2048);
+        assert_eq!(parse_size(" 3g ").unwrap(), 3 << 30);
+        assert_eq!(parse_size("1T").unwrap(), 1 << 40);
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("12X").is_err());
+        assert!(parse_size("-1").is_err());
+        // overflows u64
+        assert!(parse_size("99999999999T").is_err());
+    }
+}